@@ -1,3 +1,5 @@
+use std::{io::Write, time::Duration};
+
 use clap::*;
 use tokio::{
     fs::{read_to_string, File},
@@ -5,9 +7,33 @@ use tokio::{
 };
 
 use rust_firestore_snapshot_core::firestore::{
-    collect::collect_collection,
+    anonymize::AnonymizationProfile,
+    bulk::bulk_seed_collection,
+    copy::copy_collection,
+    delete::{delete_recursive, list_subtree},
+    diff::{diff_collections, diff_with_firestore},
+    fanout::{resolve_parent_pattern, seed_collection_fan_out, ParentDocuments},
     get_client,
-    seed::{seed_collection, CollectionData},
+    migrate::{migrate_collection, Migration, MigrationProgress, MigrationReport},
+    plan::{plan_seed, PlannedOperationKind, SeedPlan},
+    references::{
+        include_referenced_documents_transformed, seed_referenced_documents, ReferencedCollection,
+        ReferencedDocuments, Snapshot,
+    },
+    relocate::{move_subtree, rollback_move},
+    replace::replace_collection,
+    retry::RetryPolicy,
+    rollback::{rollback, RollbackSnapshot},
+    sample::{sample_collection_transformed, SampleOptions},
+    seed::{
+        seed_collection_with_options, CollectionData, PathRemap, SeedOptions, SeedPrecondition,
+    },
+    sync::{sync_collection, validate_sync_options},
+    transform::{
+        collect_collection_resumable_transformed, collect_collection_transformed,
+        transform_collection, DeclarativeTransform, DocumentTransformer, TransformerChain,
+    },
+    versioning::{load_migrations, migrate_up},
     FirestoreConnection,
 };
 
@@ -20,17 +46,146 @@ struct CliArgs {
     mode: Mode,
 
     /// Path to the collection in Firestore
-    /// Required in `get` mode.
+    /// Required in `get`, `copy` and `move` modes.
+    /// In `delete` and `move` modes it can also be a path to a document.
     #[clap(short, long)]
     collection: Option<String>,
 
     /// Path to the parent document for the collection in Firestore.
     ///
     /// Collection from the JSON file would be saved as a subcollection of a document found on this path.
-    /// Required in `post` mode.
+    /// Required in `post`, `patch`, `put`, `sync` and `copy` modes, and in `diff` mode unless `--against` is given.
     #[clap(short, long)]
     parent_document: Option<String>,
 
+    /// Paths to many parent documents, each of them gets a copy of the collection from the JSON file.
+    /// Can be repeated, or given once as a pattern where `*` matches any ID, e.g. `tenants/*`.
+    /// Used instead of `parent_document` in `post` and `patch` modes. With `dry_run` or `bulk`
+    /// the parents are previewed or written one at a time.
+    #[clap(long, multiple_occurrences = true)]
+    parents: Vec<String>,
+
+    /// Only create documents which do not exist yet, existing documents are left untouched.
+    /// Used in `post`, `patch` and `sync` modes.
+    #[clap(long, conflicts_with = "update-only")]
+    create_only: bool,
+
+    /// Only update documents which already exist, missing documents are not created.
+    /// Used in `post`, `patch` and `sync` modes.
+    #[clap(long)]
+    update_only: bool,
+
+    /// Write documents independently with non-atomic batch writes and report the status of each of them.
+    /// The writes are not committed in batches, so it can't be combined with `journal`.
+    /// Used in `post` and `patch` modes.
+    #[clap(long, conflicts_with = "journal")]
+    bulk: bool,
+
+    /// Save the progress of the export to `<file>.checkpoint` and resume an interrupted export from it.
+    /// Used in `get` mode.
+    #[clap(long)]
+    resumable: bool,
+
+    /// Maximum number of batches committed at the same time.
+    /// Used in `post`, `patch`, `put` and `sync` modes.
+    #[clap(long, default_value = "1")]
+    concurrency: usize,
+
+    /// Path to a journal file recording the committed batches.
+    /// Used in `post`, `patch` and `put` modes.
+    #[clap(long)]
+    journal: Option<String>,
+
+    /// Path to a file the prior state of the documents is saved to before they are written.
+    /// The file can be restored in `rollback` mode.
+    /// Used in `post`, `patch`, `put` and `sync` modes.
+    #[clap(long)]
+    undo_log: Option<String>,
+
+    /// Project id replacing the project of the references in the written documents.
+    /// The documents themselves are written into the project given by `project-id`.
+    /// Used in `post`, `patch` and `put` modes.
+    #[clap(long)]
+    remap_project: Option<String>,
+
+    /// Database id replacing the database of the references in the written documents.
+    /// Used in `post`, `patch` and `put` modes.
+    #[clap(long)]
+    remap_database: Option<String>,
+
+    /// Rewrites a path prefix of the written documents and of the references, in the form `from=to`.
+    /// Can be repeated, the first matching prefix is used.
+    /// Used in `post`, `patch` and `put` modes.
+    #[clap(long, multiple_occurrences = true)]
+    remap_prefix: Vec<String>,
+
+    /// Give every document a fresh auto-ID, rewriting references to documents of the file.
+    /// Used in `post` and `patch` modes.
+    #[clap(long)]
+    regenerate_ids: bool,
+
+    /// Skip the batches recorded in the journal by an interrupted run.
+    #[clap(long, requires = "journal", conflicts_with = "regenerate-ids")]
+    resume: bool,
+
+    /// Path to a JSON file with a list of transforms applied to every document, e.g.
+    /// `[{"op": "delete", "field": "password"}, {"drop_where": {"test": {"BooleanValue": true}}}]`.
+    /// Used in `get`, `sample`, `post`, `patch`, `put` and `sync` modes.
+    #[clap(long)]
+    transforms: Option<String>,
+
+    /// Path to a JSON file with the anonymization profile scrubbing personal data, applied after `transforms`.
+    /// Exported documents are anonymized while they are collected, so personal data is never
+    /// written to the file, the checkpoint of `resumable` or the referenced documents.
+    /// Used in `get`, `sample`, `post`, `patch`, `put` and `sync` modes.
+    #[clap(long)]
+    anonymize: Option<String>,
+
+    /// Only print what would be changed, without writing anything.
+    /// Used in `post`, `patch`, `put`, `delete`, `sync`, `rollback`, `move`, `migrate` and `migrate-up` modes.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Path to another JSON file the file is compared with in `diff` mode.
+    ///
+    /// When missing, the file is compared with the collection stored in Firestore.
+    #[clap(long)]
+    against: Option<String>,
+
+    /// Print the differences as a JSON Patch (RFC 6902) instead of a readable summary.
+    /// Used in `diff` mode.
+    #[clap(long)]
+    json_patch: bool,
+
+    /// Maximum number of attempts of a Firestore call failing with a transient error.
+    #[clap(long, default_value = "5")]
+    max_attempts: usize,
+
+    /// Delay in milliseconds before the first retry of a failed Firestore call.
+    #[clap(long, default_value = "100")]
+    initial_backoff_ms: u64,
+
+    /// Upper bound in milliseconds of the delay between retries.
+    #[clap(long, default_value = "10000")]
+    max_backoff_ms: u64,
+
+    /// Factor the delay between retries grows by after every retry.
+    #[clap(long, default_value = "2.0")]
+    backoff_multiplier: f64,
+
+    /// Fraction of the delay between retries randomly added or subtracted, between 0 and 1.
+    #[clap(long, default_value = "0.2")]
+    retry_jitter: f64,
+
+    /// Status code of Firestore calls which is retried, e.g. `UNAVAILABLE`. Can be repeated.
+    /// When missing, `UNAVAILABLE`, `ABORTED`, `RESOURCE_EXHAUSTED` and `DEADLINE_EXCEEDED` are retried.
+    #[clap(long, multiple_occurrences = true)]
+    retriable_code: Vec<String>,
+
+    /// Skip the confirmation prompt of destructive modes.
+    #[clap(short, long)]
+    yes: bool,
+
     /// Path to the file
     file: Option<String>,
 
@@ -38,6 +193,58 @@ struct CliArgs {
     #[clap(short, long)]
     project_id: String,
 
+    /// Path the collection or the document is moved to in `move` mode.
+    #[clap(long)]
+    destination: Option<String>,
+
+    /// Path to the file recording the progress of a move.
+    /// Used in `move` mode.
+    #[clap(long, default_value = "move-progress.json")]
+    progress_file: String,
+
+    /// Revert the move recorded in the progress file instead of moving.
+    /// Used in `move` mode.
+    #[clap(long)]
+    revert: bool,
+
+    /// The Firebase project id the collection is copied to in `copy` mode.
+    /// When missing, the collection is copied within the same project.
+    #[clap(long)]
+    to_project: Option<String>,
+
+    /// Path to the directory with the numbered migration files, e.g. `0001_add_status.json`.
+    /// Used in `migrate-up` mode.
+    #[clap(long, default_value = "migrations")]
+    migrations_dir: String,
+
+    /// Path to the collection recording the applied migrations.
+    /// Used in `migrate-up` mode.
+    #[clap(long, default_value = "_migrations")]
+    tracking_collection: String,
+
+    /// Share of the documents picked in `sample` mode, between 0 and 1.
+    #[clap(long, default_value = "0.01")]
+    fraction: f64,
+
+    /// Pick the same documents on every run with the same seed instead of random ones.
+    /// Used in `sample` mode.
+    #[clap(long)]
+    sample_seed: Option<u64>,
+
+    /// Include the documents referenced from the sample, so that it has no dangling references.
+    /// Documents of other collections are saved under their own paths in the `references` of the file.
+    /// They are seeded before the collection in `post`, `patch`, `put` and `sync` modes and keep their IDs.
+    /// Used in `sample` mode.
+    #[clap(long)]
+    follow_references: bool,
+
+    /// Follow references of the exported documents up to this many steps, e.g. `1` for the directly referenced documents.
+    /// Documents of other collections are saved under their own paths in the `references` of the file,
+    /// see `follow_references`.
+    /// Used in `get` mode.
+    #[clap(long)]
+    reference_depth: Option<usize>,
+
     /// The Firebase Auth Access Token
     /// It can be obtained by calling `gcloud auth print-access-token`
     #[clap(short, long)]
@@ -45,13 +252,40 @@ struct CliArgs {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 enum Mode {
     GET,
+    /// Saves a subset of the documents of the collection, together with their subcollections
+    SAMPLE,
+    /// Overwrites documents with the data from the file
     POST,
+    /// Merges the data from the file into documents, preserving fields not present in the file
+    PATCH,
+    /// Replaces the collection with the data from the file, deleting documents not present in the file
+    PUT,
+    /// Deletes the collection or the document together with all subcollections
+    DELETE,
+    /// Shows differences between the file and the collection in Firestore or another file
+    DIFF,
+    /// Mirrors the file in Firestore writing only new and changed documents and deleting documents not present in the file
+    SYNC,
+    /// Restores the documents to the state saved in the undo log file
+    ROLLBACK,
+    /// Copies the collection to the parent document, possibly in another project
+    COPY,
+    /// Moves the collection or the document together with all subcollections to the destination path
+    MOVE,
+    /// Applies the field migration from the file to every document of its collection
+    MIGRATE,
+    /// Applies the migrations from `migrations-dir` which are not recorded in the tracking collection yet
+    #[clap(name = "migrate-up")]
+    MIGRATEUP,
 }
 
 pub async fn run_cli_app() {
     let args = CliArgs::parse();
+    let options = seed_options(&args);
+    let retry_policy = retry_policy(&args);
 
     // setup connection to Firestore
     let (client, project_id) = (
@@ -61,46 +295,874 @@ pub async fn run_cli_app() {
         args.project_id,
     );
     let parent = format!("projects/{}/databases/(default)/documents", project_id);
-    let firestore_conn = FirestoreConnection(client, parent);
+    let firestore_conn = FirestoreConnection::new(client, parent).with_retry_policy(retry_policy);
+    let to_project = args.to_project.unwrap_or_else(|| project_id.clone());
     let filename = args.file.unwrap_or(String::from("data.json"));
+    // an empty chain keeps the documents as they are
+    let mut transformer = match &args.transforms {
+        Some(transforms_filename) => read_transforms_file(transforms_filename).await,
+        None => TransformerChain::default(),
+    };
+    if let Some(profile_filename) = &args.anonymize {
+        let json_string = read_to_string(profile_filename)
+            .await
+            .unwrap_or_else(|_| panic!("Could not read the profile from {}", profile_filename));
+        let profile: AnonymizationProfile = serde_json::from_str(&json_string)
+            .unwrap_or_else(|error| panic!("Could not parse {}: {}", profile_filename, error));
+        transformer.push(Box::new(profile));
+    }
+    let parent_path_or_root = args.parent_document.clone().unwrap_or_else(|| "/".into());
 
     match args.mode {
         Mode::GET => {
-            let path = format!(
-                "{}{}",
-                firestore_conn.1,
-                args.collection
-                    .expect("`collection` is required in `get` mode.")
-            );
-            let collection = collect_collection(firestore_conn, path.to_string())
+            let collection_path = args
+                .collection
+                .expect("`collection` is required in `get` mode.");
+            let path = format!("{}{}", firestore_conn.1, collection_path);
+            let mut collection = if args.resumable {
+                let checkpoint_path = format!("{}.checkpoint", filename);
+                collect_collection_resumable_transformed(
+                    firestore_conn.clone(),
+                    path.to_string(),
+                    &checkpoint_path,
+                    &transformer,
+                )
                 .await
-                .unwrap();
-            let json_string =
-                serde_json::to_string_pretty(&collection).expect("The data could not be parsed");
-            let mut file = File::create(filename).await.unwrap();
-            file.write_all(json_string.into_bytes().as_slice())
+                .unwrap()
+            } else {
+                collect_collection_transformed(
+                    firestore_conn.clone(),
+                    path.to_string(),
+                    &transformer,
+                )
                 .await
-                .expect("Could not write a file");
-        }
-        Mode::POST => {
-            let json_string = read_to_string(&filename)
+                .unwrap()
+            };
+            // references are followed from the transformed documents
+            let referenced = match args.reference_depth {
+                Some(depth) => include_referenced_documents_transformed(
+                    firestore_conn,
+                    &mut collection,
+                    &path,
+                    Some(depth),
+                    &transformer,
+                )
                 .await
-                .expect(&format!("Could not read data from {filename}"));
+                .unwrap_or_else(|error| {
+                    panic!("Error while trying to follow references: {}", error)
+                }),
+                None => ReferencedDocuments::default(),
+            };
+            save_snapshot(&filename, collection, referenced).await;
+        }
+        Mode::SAMPLE => {
+            let collection_path = args
+                .collection
+                .expect("`collection` is required in `sample` mode.");
+            let path = format!("{}{}", firestore_conn.1, collection_path);
+            let sample_options = SampleOptions {
+                fraction: args.fraction,
+                seed: args.sample_seed,
+                follow_references: args.follow_references,
+            };
+            let sample = sample_collection_transformed(
+                firestore_conn,
+                path.clone(),
+                &sample_options,
+                &transformer,
+            )
+            .await
+            .unwrap_or_else(|error| panic!("Error while trying to sample {}: {}", &path, error));
+            println!("{} documents sampled.", sample.collection.documents.len());
+            save_snapshot(&filename, sample.collection, sample.referenced).await;
+        }
+        Mode::POST | Mode::PATCH => {
+            let Snapshot {
+                collection: post_body,
+                references,
+            } = read_transformed_snapshot_file(&filename, &parent_path_or_root, &transformer).await;
+
+            seed_references(&firestore_conn, &references, &options, args.dry_run).await;
+
+            if args.dry_run || args.bulk {
+                // a fan-out is previewed or written in bulk one parent at a time
+                let parent_paths = match (args.parents.as_slice(), &args.parent_document) {
+                    ([], Some(parent_path)) => vec![parent_path.clone()],
+                    ([], None) => panic!(
+                        "`parent_document` or `parents` is required in `post` and `patch` modes."
+                    ),
+                    ([pattern], _) if pattern.contains('*') => {
+                        resolve_parent_pattern(firestore_conn.clone(), pattern)
+                            .await
+                            .unwrap_or_else(|error| {
+                                panic!(
+                                    "Error while trying to resolve the parents matching {}: {}",
+                                    pattern, error
+                                )
+                            })
+                    }
+                    (paths, _) => paths.to_vec(),
+                };
+                if args.bulk && args.undo_log.is_some() && parent_paths.len() > 1 {
+                    panic!("`undo_log` can't be combined with `bulk` for many parents, every parent would overwrite it.");
+                }
+                for parent_path in &parent_paths {
+                    if !args.parents.is_empty() {
+                        println!("{}:", parent_path);
+                    }
+                    if args.dry_run {
+                        let plan =
+                            plan_seed(firestore_conn.clone(), &post_body, parent_path, &options)
+                                .await
+                                .unwrap_or_else(|error| {
+                                    panic!(
+                                "Error while trying to plan seeding a collection for {}: {}",
+                                parent_path, error
+                            )
+                                });
+                        print_seed_plan(&plan);
+                        continue;
+                    }
+                    match bulk_seed_collection(
+                        firestore_conn.clone(),
+                        &post_body,
+                        parent_path,
+                        &options,
+                    )
+                    .await
+                    {
+                        Ok(report) => {
+                            println!(
+                                "Collection updated. {} of {} records written.",
+                                report.written(),
+                                report.statuses.len()
+                            );
+                            for (old_name, new_name) in &report.id_mapping {
+                                println!("{} -> {}", old_name, new_name);
+                            }
+                            for failed in report.failed() {
+                                println!(
+                                    "Failed {} after {} attempts: {:?} {}",
+                                    failed.path,
+                                    failed.attempts,
+                                    failed.code(),
+                                    failed.message
+                                );
+                            }
+                        }
+                        Err(error) => panic!(
+                            "Error while trying to seed a collection for {}: {}",
+                            parent_path, error
+                        ),
+                    };
+                }
+                return;
+            }
 
-            let post_body: CollectionData =
-                serde_json::from_str(&json_string).expect(&format!("Could not parse {filename}"));
+            if !args.parents.is_empty() {
+                let parents = match args.parents.as_slice() {
+                    [pattern] if pattern.contains('*') => ParentDocuments::Pattern(pattern.clone()),
+                    paths => ParentDocuments::Paths(paths.to_vec()),
+                };
+                match seed_collection_fan_out(firestore_conn, &post_body, &parents, &options).await
+                {
+                    Ok(report) => {
+                        for parent in &report.parents {
+                            println!(
+                                "{}: {} records written, {} skipped.",
+                                parent.parent,
+                                parent.report.written,
+                                parent.report.skipped.len()
+                            );
+                        }
+                        println!(
+                            "Collection seeded successfully under {} parents.",
+                            report.parents.len()
+                        );
+                    }
+                    Err(error) => panic!(
+                        "Error while trying to seed a collection for {:?}: {}",
+                        &args.parents, error
+                    ),
+                };
+                return;
+            }
 
             let parent_path = args
                 .parent_document
-                .expect("`parent_document` is required in `post` mode.");
+                .expect("`parent_document` or `parents` is required in `post` and `patch` modes.");
 
-            match seed_collection(firestore_conn, &post_body, &parent_path).await {
-                Ok(count) => println!("Collection updated successfully. {count} records written."),
+            match seed_collection_with_options(firestore_conn, &post_body, &parent_path, &options)
+                .await
+            {
+                Ok(report) => {
+                    println!(
+                        "Collection updated successfully. {} records written.",
+                        report.written
+                    );
+                    for (old_name, new_name) in &report.id_mapping {
+                        println!("{} -> {}", old_name, new_name);
+                    }
+                    if report.resumed_batches > 0 {
+                        println!(
+                            "{} batches were already committed according to the journal.",
+                            report.resumed_batches
+                        );
+                    }
+                    for skipped in report.skipped {
+                        println!("Skipped {}: {:?}", skipped.path, skipped.reason);
+                    }
+                }
                 Err(error) => panic!(
                     "Error while trying to seed a collection for {}: {}",
                     &parent_path, error
                 ),
             };
         }
+        Mode::PUT => {
+            let Snapshot {
+                collection: post_body,
+                references,
+            } = read_transformed_snapshot_file(&filename, &parent_path_or_root, &transformer).await;
+
+            let parent_path = args
+                .parent_document
+                .expect("`parent_document` is required in `put` mode.");
+
+            let preview = replace_collection(
+                firestore_conn.clone(),
+                &post_body,
+                &parent_path,
+                &options,
+                true,
+            )
+            .await
+            .unwrap_or_else(|error| {
+                panic!(
+                    "Error while trying to preview replacing a collection for {}: {}",
+                    &parent_path, error
+                )
+            });
+            for name in &preview.writes {
+                println!("write  {}", name);
+            }
+            for name in &preview.deletes {
+                println!("delete {}", name);
+            }
+            println!(
+                "{} documents would be written and {} documents would be deleted.",
+                preview.writes.len(),
+                preview.deletes.len()
+            );
+            if args.dry_run {
+                seed_references(&firestore_conn, &references, &options, true).await;
+                return;
+            }
+            if !args.yes && !confirm("Replace the collection?") {
+                println!("Aborted.");
+                return;
+            }
+            seed_references(&firestore_conn, &references, &options, false).await;
+
+            match replace_collection(firestore_conn, &post_body, &parent_path, &options, false)
+                .await
+            {
+                Ok(report) => {
+                    println!(
+                        "Collection replaced successfully. {} records written, {} records deleted.",
+                        report.seed.written, report.deleted
+                    );
+                    for skipped in report.seed.skipped {
+                        println!("Skipped {}: {:?}", skipped.path, skipped.reason);
+                    }
+                }
+                Err(error) => panic!(
+                    "Error while trying to replace a collection for {}: {}",
+                    &parent_path, error
+                ),
+            };
+        }
+        Mode::DELETE => {
+            let path = args
+                .collection
+                .expect("`collection` is required in `delete` mode.");
+
+            let names = list_subtree(firestore_conn.clone(), &path)
+                .await
+                .unwrap_or_else(|error| panic!("Error while trying to list {}: {}", &path, error));
+            for name in &names {
+                println!("delete {}", name);
+            }
+            println!("{} documents would be deleted.", names.len());
+            if args.dry_run {
+                return;
+            }
+            if !args.yes && !confirm(&format!("Delete {} recursively?", &path)) {
+                println!("Aborted.");
+                return;
+            }
+
+            match delete_recursive(firestore_conn, &path, |progress| {
+                println!("Deleted {}/{} documents", progress.deleted, progress.total)
+            })
+            .await
+            {
+                Ok(count) => println!("{} deleted successfully. {count} records deleted.", &path),
+                Err(error) => panic!("Error while trying to delete {}: {}", &path, error),
+            };
+        }
+        Mode::DIFF => {
+            let snapshot = read_collection_file(&filename).await;
+
+            let diff = match args.against {
+                Some(other_filename) => {
+                    let other = read_collection_file(&other_filename).await;
+                    diff_collections(&snapshot, &other)
+                }
+                None => {
+                    let parent_path = args.parent_document.expect(
+                        "`parent_document` is required in `diff` mode unless `against` is given.",
+                    );
+                    diff_with_firestore(firestore_conn, &snapshot, &parent_path)
+                        .await
+                        .unwrap_or_else(|error| {
+                            panic!(
+                                "Error while trying to compare a collection for {}: {}",
+                                &parent_path, error
+                            )
+                        })
+                }
+            };
+            if args.json_patch {
+                let json_string = serde_json::to_string_pretty(&diff.to_json_patch())
+                    .expect("The diff could not be converted to JSON");
+                println!("{}", json_string);
+            } else {
+                println!("{}", diff);
+            }
+        }
+        Mode::SYNC => {
+            if !args.parents.is_empty() || args.bulk {
+                panic!("`parents` and `bulk` are not supported in `sync` mode.");
+            }
+            validate_sync_options(&options).unwrap_or_else(|error| panic!("{}", error));
+            let Snapshot {
+                collection: snapshot,
+                references,
+            } = read_transformed_snapshot_file(&filename, &parent_path_or_root, &transformer).await;
+
+            let parent_path = args
+                .parent_document
+                .expect("`parent_document` is required in `sync` mode.");
+
+            let diff = diff_with_firestore(firestore_conn.clone(), &snapshot, &parent_path)
+                .await
+                .unwrap_or_else(|error| {
+                    panic!(
+                        "Error while trying to compare a collection for {}: {}",
+                        &parent_path, error
+                    )
+                });
+            println!("{}", diff);
+            if args.dry_run {
+                seed_references(&firestore_conn, &references, &options, true).await;
+                return;
+            }
+            if diff.is_empty() && references.is_empty() {
+                return;
+            }
+            if !args.yes && !confirm("Synchronize the collection?") {
+                println!("Aborted.");
+                return;
+            }
+            seed_references(&firestore_conn, &references, &options, false).await;
+
+            match sync_collection(firestore_conn, &snapshot, &parent_path, &options).await {
+                Ok(report) => println!(
+                    "Collection synchronized successfully. {} created, {} updated, {} deleted, {} unchanged.",
+                    report.created, report.updated, report.deleted, report.unchanged
+                ),
+                Err(error) => panic!(
+                    "Error while trying to synchronize a collection for {}: {}",
+                    &parent_path, error
+                ),
+            };
+        }
+        Mode::COPY => {
+            let source_path = args
+                .collection
+                .expect("`collection` is required in `copy` mode.");
+            let parent_path = args
+                .parent_document
+                .expect("`parent_document` is required in `copy` mode.");
+            let destination_conn = FirestoreConnection(
+                firestore_conn.0.clone(),
+                format!("projects/{}/databases/(default)/documents", to_project),
+                firestore_conn.2.clone(),
+            );
+
+            match copy_collection(
+                firestore_conn,
+                &source_path,
+                destination_conn,
+                &parent_path,
+                &options,
+            )
+            .await
+            {
+                Ok(report) => {
+                    println!(
+                        "Collection copied successfully. {} records written.",
+                        report.written
+                    );
+                    for skipped in report.skipped {
+                        println!("Skipped {}: {:?}", skipped.path, skipped.reason);
+                    }
+                }
+                Err(error) => panic!(
+                    "Error while trying to copy {} to {}: {}",
+                    &source_path, &parent_path, error
+                ),
+            };
+        }
+        Mode::MOVE => {
+            if args.revert {
+                if !args.yes
+                    && !confirm(&format!(
+                        "Revert the move recorded in {}?",
+                        &args.progress_file
+                    ))
+                {
+                    println!("Aborted.");
+                    return;
+                }
+                match rollback_move(firestore_conn, &args.progress_file).await {
+                    Ok(report) => println!(
+                        "Move reverted successfully. {} records restored.",
+                        report.copied
+                    ),
+                    Err(error) => panic!("Error while trying to revert the move: {}", error),
+                };
+                return;
+            }
+
+            let source_path = args
+                .collection
+                .expect("`collection` is required in `move` mode.");
+            let destination = args
+                .destination
+                .expect("`destination` is required in `move` mode.");
+
+            let names = list_subtree(firestore_conn.clone(), &source_path)
+                .await
+                .unwrap_or_else(|error| {
+                    panic!("Error while trying to list {}: {}", &source_path, error)
+                });
+            println!(
+                "{} documents would be moved from {} to {}.",
+                names.len(),
+                &source_path,
+                &destination
+            );
+            if args.dry_run {
+                return;
+            }
+            if !args.yes && !confirm(&format!("Move {} to {}?", &source_path, &destination)) {
+                println!("Aborted.");
+                return;
+            }
+
+            match move_subtree(
+                firestore_conn,
+                &source_path,
+                &destination,
+                &args.progress_file,
+            )
+            .await
+            {
+                Ok(report) => println!(
+                    "{} moved successfully. {} records copied, {} records deleted.",
+                    &source_path, report.copied, report.deleted
+                ),
+                Err(error) => panic!(
+                    "Error while trying to move {}, run again to complete the move or revert it with `--revert`: {}",
+                    &source_path, error
+                ),
+            };
+        }
+        Mode::MIGRATE => {
+            let json_string = read_to_string(&filename)
+                .await
+                .unwrap_or_else(|_| panic!("Could not read data from {}", filename));
+            let migration: Migration = serde_json::from_str(&json_string)
+                .unwrap_or_else(|error| panic!("Could not parse {}: {}", filename, error));
+            let print_progress = |progress: MigrationProgress| {
+                println!(
+                    "{} documents scanned, {} to migrate.",
+                    progress.scanned, progress.migrated
+                )
+            };
+
+            let preview =
+                migrate_collection(firestore_conn.clone(), &migration, true, print_progress)
+                    .await
+                    .unwrap_or_else(|error| {
+                        panic!(
+                            "Error while trying to read {}: {}",
+                            &migration.collection, error
+                        )
+                    });
+            print_migration_report(&preview);
+            if args.dry_run || preview.migrated.is_empty() {
+                return;
+            }
+            if !args.yes
+                && !confirm(&format!(
+                    "Migrate {} documents of {}?",
+                    preview.migrated.len(),
+                    &migration.collection
+                ))
+            {
+                println!("Aborted.");
+                return;
+            }
+
+            match migrate_collection(firestore_conn, &migration, false, print_progress).await {
+                Ok(report) => {
+                    println!(
+                        "Migration finished successfully. {} records migrated.",
+                        report.migrated.len()
+                    );
+                    for failed in report.failed {
+                        println!("Failed {}: {}", failed.path, failed.reason);
+                    }
+                }
+                Err(error) => panic!(
+                    "Error while trying to migrate {}: {}",
+                    &migration.collection, error
+                ),
+            };
+        }
+        Mode::MIGRATEUP => {
+            let migrations_dir = &args.migrations_dir;
+            let migrations = load_migrations(migrations_dir).unwrap_or_else(|error| {
+                panic!(
+                    "Could not read migrations from {}: {}",
+                    migrations_dir, error
+                )
+            });
+            if !args.dry_run
+                && !args.yes
+                && !confirm(&format!(
+                    "Apply pending migrations from {}?",
+                    &args.migrations_dir
+                ))
+            {
+                println!("Aborted.");
+                return;
+            }
+
+            match migrate_up(
+                firestore_conn,
+                &migrations,
+                &args.tracking_collection,
+                args.dry_run,
+                |migration, progress| {
+                    println!(
+                        "{}: {} documents scanned, {} migrated.",
+                        migration.id, progress.scanned, progress.migrated
+                    )
+                },
+            )
+            .await
+            {
+                Ok(report) => {
+                    for applied in &report.applied {
+                        println!("Migration {}:", applied.id);
+                        print_migration_report(&applied.report);
+                    }
+                    let verb = if report.dry_run {
+                        "would be applied"
+                    } else {
+                        "applied"
+                    };
+                    println!(
+                        "{} migrations {}, {} applied before.",
+                        report.applied.len(),
+                        verb,
+                        report.already_applied
+                    );
+                }
+                Err(error) => panic!("Error while trying to apply migrations: {}", error),
+            };
+        }
+        Mode::ROLLBACK => {
+            let json_string = read_to_string(&filename)
+                .await
+                .unwrap_or_else(|_| panic!("Could not read data from {}", filename));
+            let snapshot: RollbackSnapshot = serde_json::from_str(&json_string)
+                .unwrap_or_else(|error| panic!("Could not parse {}: {}", filename, error));
+
+            for document in &snapshot.documents {
+                let action = if document.data.is_some() {
+                    "restore"
+                } else {
+                    "delete"
+                };
+                println!("{:<8} {}", action, document.name);
+            }
+            println!("{} documents would be restored.", snapshot.documents.len());
+            if args.dry_run {
+                return;
+            }
+            if !args.yes && !confirm("Roll the documents back?") {
+                println!("Aborted.");
+                return;
+            }
+
+            match rollback(firestore_conn, &snapshot).await {
+                Ok(report) => println!(
+                    "Rollback finished successfully. {} records restored, {} records deleted.",
+                    report.restored, report.deleted
+                ),
+                Err(error) => panic!("Error while trying to roll back {}: {}", &filename, error),
+            };
+        }
+    }
+}
+
+fn seed_options(args: &CliArgs) -> SeedOptions {
+    let precondition = if args.create_only {
+        SeedPrecondition::MustNotExist
+    } else if args.update_only {
+        SeedPrecondition::MustExist
+    } else {
+        SeedPrecondition::None
+    };
+    SeedOptions {
+        merge: args.mode == Mode::PATCH,
+        precondition,
+        concurrency: args.concurrency,
+        journal: args.journal.clone(),
+        resume: args.resume,
+        undo_log: args.undo_log.clone(),
+        regenerate_ids: args.regenerate_ids,
+        remap: PathRemap {
+            project: args.remap_project.clone(),
+            database: args.remap_database.clone(),
+            prefixes: args
+                .remap_prefix
+                .iter()
+                .map(|rule| {
+                    let (from, to) = rule.split_once('=').unwrap_or_else(|| {
+                        panic!("Invalid prefix rule {}, expected `from=to`", rule)
+                    });
+                    (from.to_string(), to.to_string())
+                })
+                .collect(),
+        },
+    }
+}
+
+fn retry_policy(args: &CliArgs) -> RetryPolicy {
+    let default = RetryPolicy::default();
+    let retriable_codes = if args.retriable_code.is_empty() {
+        default.retriable_codes
+    } else {
+        args.retriable_code
+            .iter()
+            .map(|name| RetryPolicy::parse_code(name).unwrap_or_else(|error| panic!("{}", error)))
+            .collect()
+    };
+    RetryPolicy {
+        max_attempts: args.max_attempts,
+        initial_backoff: Duration::from_millis(args.initial_backoff_ms),
+        max_backoff: Duration::from_millis(args.max_backoff_ms),
+        multiplier: args.backoff_multiplier,
+        jitter: args.retry_jitter,
+        retriable_codes,
+    }
+}
+
+fn print_seed_plan(plan: &SeedPlan) {
+    for batch in &plan.batches {
+        println!(
+            "Batch {} ({} writes)",
+            batch.index + 1,
+            batch.operations.len()
+        );
+        for operation in &batch.operations {
+            let kind = match operation.kind {
+                PlannedOperationKind::Create => "create",
+                PlannedOperationKind::Overwrite => "overwrite",
+                PlannedOperationKind::Unchanged => "unchanged",
+                PlannedOperationKind::Skip => "skip",
+            };
+            println!("  {:<10} {}", kind, operation.path);
+        }
+    }
+    println!(
+        "{} to create, {} to overwrite, {} unchanged, {} to skip in {} batches.",
+        plan.count(PlannedOperationKind::Create),
+        plan.count(PlannedOperationKind::Overwrite),
+        plan.count(PlannedOperationKind::Unchanged),
+        plan.count(PlannedOperationKind::Skip),
+        plan.batches.len()
+    );
+}
+
+fn print_migration_report(report: &MigrationReport) {
+    for document in &report.migrated {
+        println!("{} ({})", document.path, document.fields.join(", "));
+    }
+    for failed in &report.failed {
+        println!("Failed {}: {}", failed.path, failed.reason);
+    }
+    println!(
+        "{} of {} documents would be migrated, {} failed.",
+        report.migrated.len(),
+        report.scanned,
+        report.failed.len()
+    );
+}
+
+/// Saves the collection together with the referenced documents of other collections to the file.
+async fn save_snapshot(
+    filename: &str,
+    collection: CollectionData,
+    referenced: ReferencedDocuments,
+) {
+    let referenced_count = referenced
+        .collections
+        .iter()
+        .map(|referenced| referenced.collection.documents.len())
+        .sum::<usize>();
+    let snapshot = Snapshot {
+        collection,
+        references: referenced.collections,
+    };
+    let json_string =
+        serde_json::to_string_pretty(&snapshot).expect("The data could not be parsed");
+    let mut file = File::create(filename).await.unwrap();
+    file.write_all(json_string.into_bytes().as_slice())
+        .await
+        .expect("Could not write a file");
+    if referenced_count > 0 {
+        println!(
+            "{} referenced documents of other collections saved to {}.",
+            referenced_count, filename
+        );
+    }
+    for name in referenced.missing {
+        println!("Missing referenced document {}", name);
+    }
+}
+
+/// Seeds the referenced documents of the snapshot under their parents, or only prints the plan.
+async fn seed_references(
+    conn: &FirestoreConnection,
+    references: &[ReferencedCollection],
+    options: &SeedOptions,
+    dry_run: bool,
+) {
+    if references.is_empty() {
+        return;
+    }
+    if dry_run {
+        for referenced in references {
+            let plan = plan_seed(
+                conn.clone(),
+                &referenced.collection,
+                &referenced.parent,
+                options,
+            )
+            .await
+            .unwrap_or_else(|error| {
+                panic!(
+                    "Error while trying to plan seeding the referenced documents under {}: {}",
+                    referenced.parent, error
+                )
+            });
+            print_seed_plan(&plan);
+        }
+        return;
+    }
+    match seed_referenced_documents(conn.clone(), references, options).await {
+        Ok(report) => {
+            println!("{} referenced documents written.", report.written);
+            for skipped in report.skipped {
+                println!("Skipped {}: {:?}", skipped.path, skipped.reason);
+            }
+        }
+        Err(error) => panic!(
+            "Error while trying to seed the referenced documents: {}",
+            error
+        ),
     }
 }
+
+async fn read_transforms_file(filename: &str) -> TransformerChain {
+    let json_string = read_to_string(filename)
+        .await
+        .unwrap_or_else(|_| panic!("Could not read transforms from {}", filename));
+    let transforms: Vec<DeclarativeTransform> = serde_json::from_str(&json_string)
+        .unwrap_or_else(|error| panic!("Could not parse {}: {}", filename, error));
+
+    TransformerChain::new(
+        transforms
+            .into_iter()
+            .map(|transform| Box::new(transform) as Box<dyn DocumentTransformer>)
+            .collect(),
+    )
+}
+
+/// Reads the snapshot to seed under the parent, passing its documents and the referenced documents
+/// through the transformer.
+async fn read_transformed_snapshot_file(
+    filename: &str,
+    parent_path: &str,
+    transformer: &TransformerChain,
+) -> Snapshot {
+    let json_string = read_to_string(filename)
+        .await
+        .unwrap_or_else(|_| panic!("Could not read data from {}", filename));
+    let snapshot: Snapshot = serde_json::from_str(&json_string)
+        .unwrap_or_else(|error| panic!("Could not parse {}: {}", filename, error));
+
+    let transform = |collection, parent_path: &str| {
+        transform_collection(collection, parent_path, transformer)
+            .unwrap_or_else(|error| panic!("Error while trying to transform documents: {}", error))
+    };
+    Snapshot {
+        collection: transform(snapshot.collection, parent_path),
+        references: snapshot
+            .references
+            .into_iter()
+            .map(|referenced| ReferencedCollection {
+                collection: transform(referenced.collection, &referenced.parent),
+                parent: referenced.parent,
+            })
+            .collect(),
+    }
+}
+
+async fn read_collection_file(filename: &str) -> CollectionData {
+    let json_string = read_to_string(filename)
+        .await
+        .unwrap_or_else(|_| panic!("Could not read data from {}", filename));
+
+    serde_json::from_str(&json_string).unwrap_or_else(|_| panic!("Could not parse {}", filename))
+}
+
+/// Asks the user to confirm a destructive operation on the terminal.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    std::io::stdout()
+        .flush()
+        .expect("Could not write to stdout");
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .expect("Could not read the answer");
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    fanout::matches_glob,
+    migrate::{get_field, remove_field, set_field, split_field_path},
+    seed::{DocumentData, ValueType},
+    transform::DocumentTransformer,
+    BoxError,
+};
+
+/// Rules scrubbing personal data from documents, usually read from a JSON config file.
+///
+/// The profile is a [`DocumentTransformer`], so it can be applied while collecting a collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymizationProfile {
+    /// Secret mixed into hashed and faked values. The same salt gives the same values,
+    /// so references between anonymized documents still line up.
+    pub salt: String,
+    pub rules: Vec<AnonymizationRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymizationRule {
+    /// ID of the collections the rule applies to, `*` matches any characters.
+    /// When missing, the rule applies to documents of every collection.
+    #[serde(default)]
+    pub collection: Option<String>,
+    /// Path of the field with segments separated by dots.
+    pub field: String,
+    #[serde(flatten)]
+    pub action: AnonymizationAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AnonymizationAction {
+    /// Removes the field.
+    Drop,
+    /// Replaces the value with the hex encoded HMAC-SHA256 of the value keyed by the salt.
+    Hash,
+    /// Replaces the value with a realistic fake value derived from the value and the salt.
+    Fake { kind: FakeKind },
+    /// Rounds both coordinates of a geo point to the number of decimals.
+    TruncateGeoPoint { decimals: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FakeKind {
+    Name,
+    Email,
+    Phone,
+}
+
+const FIRST_NAMES: [&str; 16] = [
+    "Alex", "Sam", "Jordan", "Taylor", "Morgan", "Casey", "Riley", "Jamie", "Avery", "Quinn",
+    "Robin", "Charlie", "Drew", "Emery", "Hayden", "Parker",
+];
+const LAST_NAMES: [&str; 16] = [
+    "Smith", "Jones", "Brown", "Garcia", "Miller", "Davis", "Wilson", "Moore", "Clark", "Lewis",
+    "Walker", "Young", "King", "Wright", "Green", "Baker",
+];
+
+impl AnonymizationProfile {
+    /// Anonymizes the fields of a document of the collection with the given ID.
+    pub fn anonymize(
+        &self,
+        collection_id: &str,
+        data: HashMap<String, ValueType>,
+    ) -> HashMap<String, ValueType> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.salt.as_bytes());
+        let mut document = ValueType::MapValue(
+            data.into_iter()
+                .map(|(field, value)| (field, Box::new(value)))
+                .collect(),
+        );
+        for rule in &self.rules {
+            if rule
+                .collection
+                .as_deref()
+                .is_some_and(|pattern| !matches_glob(pattern, collection_id))
+            {
+                continue;
+            }
+            let path = split_field_path(&rule.field);
+            let value = match get_field(&document, &path) {
+                Some(value) => value,
+                None => continue,
+            };
+            match anonymize_value(&key, &rule.action, value) {
+                Some(value) => set_field(&mut document, &path, value),
+                None => {
+                    remove_field(&mut document, &path);
+                }
+            }
+        }
+        match document {
+            ValueType::MapValue(map) => map
+                .into_iter()
+                .map(|(field, value)| (field, *value))
+                .collect(),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+impl DocumentTransformer for AnonymizationProfile {
+    fn transform(&self, path: &str, document: DocumentData) -> Result<Vec<DocumentData>, BoxError> {
+        let collection_id = path.rsplit('/').nth(1).unwrap_or_default();
+        let data = self.anonymize(collection_id, document.data);
+        Ok(vec![DocumentData { data, ..document }])
+    }
+}
+
+/// Returns the anonymized value, or `None` when the field is to be removed.
+fn anonymize_value(
+    key: &hmac::Key,
+    action: &AnonymizationAction,
+    value: &ValueType,
+) -> Option<ValueType> {
+    match action {
+        AnonymizationAction::Drop => None,
+        AnonymizationAction::Hash => Some(ValueType::StringValue(
+            digest(key, value)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        )),
+        AnonymizationAction::Fake { kind } => {
+            let digest = digest(key, value);
+            let number = u64::from_be_bytes([
+                digest[0], digest[1], digest[2], digest[3], digest[4], digest[5], digest[6],
+                digest[7],
+            ]);
+            let fake = match kind {
+                FakeKind::Name => format!(
+                    "{} {}",
+                    FIRST_NAMES[(number % 16) as usize],
+                    LAST_NAMES[(number / 16 % 16) as usize]
+                ),
+                FakeKind::Email => format!("user-{:010}@example.com", number % 10_000_000_000),
+                // 555-01XX numbers are reserved for fiction
+                FakeKind::Phone => format!(
+                    "+1-{:03}-555-01{:02}",
+                    200 + number % 800,
+                    number / 800 % 100
+                ),
+            };
+            Some(ValueType::StringValue(fake))
+        }
+        AnonymizationAction::TruncateGeoPoint { decimals } => match value {
+            ValueType::GeoPointValue((latitude, longitude)) => {
+                let factor = 10f64.powi(*decimals as i32);
+                Some(ValueType::GeoPointValue((
+                    (latitude * factor).round() / factor,
+                    (longitude * factor).round() / factor,
+                )))
+            }
+            other => Some(other.clone()),
+        },
+    }
+}
+
+/// Keyed hash of the value, strings are hashed as they are so that the same text hashes alike in any field.
+///
+/// Other values are hashed in their JSON form with the keys of maps sorted,
+/// so that equal maps hash alike whatever the order of their entries.
+fn digest(key: &hmac::Key, value: &ValueType) -> Vec<u8> {
+    let bytes = match value {
+        ValueType::StringValue(text) => text.as_bytes().to_vec(),
+        other => serde_json::to_value(other)
+            .map(canonical_json)
+            .and_then(|json| serde_json::to_vec(&json))
+            .unwrap_or_default(),
+    };
+    hmac::sign(key, &bytes).as_ref().to_vec()
+}
+
+/// Rebuilds the JSON value inserting the keys of every object in sorted order.
+fn canonical_json(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut entries = object.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonical_json(value)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(values) => {
+            serde_json::Value::Array(values.into_iter().map(canonical_json).collect())
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anonymize() {
+        let profile: AnonymizationProfile = serde_json::from_str(
+            r#"{
+                "salt": "secret",
+                "rules": [
+                    {"collection": "users", "field": "email", "action": "hash"},
+                    {"field": "contact.name", "action": "fake", "kind": "name"},
+                    {"field": "location", "action": "truncate_geo_point", "decimals": 1},
+                    {"field": "ssn", "action": "drop"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let data: HashMap<String, ValueType> = vec![
+            ("email", ValueType::StringValue("jane@example.com".into())),
+            (
+                "contact",
+                ValueType::MapValue(
+                    vec![(
+                        "name".to_string(),
+                        Box::new(ValueType::StringValue("Jane Doe".into())),
+                    )]
+                    .into_iter()
+                    .collect(),
+                ),
+            ),
+            ("location", ValueType::GeoPointValue((48.8566, 2.3522))),
+            ("ssn", ValueType::StringValue("123-45-6789".into())),
+        ]
+        .into_iter()
+        .map(|(field, value)| (field.to_string(), value))
+        .collect();
+
+        let users = profile.anonymize("users", data.clone());
+        let orders = profile.anonymize("orders", data.clone());
+
+        assert_eq!(users, profile.anonymize("users", data.clone()));
+        assert_ne!(
+            users["email"],
+            ValueType::StringValue("jane@example.com".into())
+        );
+        assert_eq!(
+            orders["email"],
+            ValueType::StringValue("jane@example.com".into())
+        );
+        assert_ne!(users["contact"], data["contact"]);
+        assert_eq!(users["location"], ValueType::GeoPointValue((48.9, 2.4)));
+        assert!(!users.contains_key("ssn"));
+    }
+
+    #[test]
+    fn test_digest_ignores_map_order() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"salt");
+        let map = |keys: &[&str]| {
+            ValueType::MapValue(
+                keys.iter()
+                    .map(|key| (key.to_string(), Box::new(ValueType::IntegerValue(1))))
+                    .collect(),
+            )
+        };
+        let first = ValueType::ArrayValue(vec![Box::new(map(&["a", "b", "c", "d", "e"]))]);
+        let second = ValueType::ArrayValue(vec![Box::new(map(&["e", "d", "c", "b", "a"]))]);
+
+        assert_eq!(
+            serde_json::to_string(&canonical_json(serde_json::to_value(&first).unwrap())).unwrap(),
+            r#"{"ArrayValue":[{"MapValue":{"a":{"IntegerValue":1},"b":{"IntegerValue":1},"c":{"IntegerValue":1},"d":{"IntegerValue":1},"e":{"IntegerValue":1}}}]}"#
+        );
+        assert_eq!(digest(&key, &first), digest(&key, &second));
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use firestore_grpc::google::rpc::Status;
+use firestore_grpc::tonic::{self, Code};
+use firestore_grpc::v1::{BatchWriteRequest, Write};
+use futures::{stream, StreamExt};
+use serde::Serialize;
+use tokio::time::sleep;
+
+use super::{
+    rollback::capture_prior_state,
+    seed::{
+        apply_options, generate_seed_writes, has_skipped_parent, is_idempotent, ordered_batches,
+        parent_document_full_path, validate_document_path, write_document_name, CollectionData,
+        SeedError, SeedOptions,
+    },
+    throttle::RampUpLimiter,
+    BoxError, FirestoreConnection,
+};
+
+/// Outcome of every write of a bulk seed, in the order of the snapshot.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BulkWriteReport {
+    pub statuses: Vec<WriteStatus>,
+    /// Number of requests retried after a transient error.
+    pub retries: usize,
+    /// Full names of the documents of the snapshot mapped to the names they were written with,
+    /// when the IDs are regenerated.
+    pub id_mapping: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WriteStatus {
+    pub path: String,
+    /// gRPC status code, `0` for applied writes.
+    pub code: i32,
+    pub message: String,
+    pub attempts: usize,
+}
+
+impl WriteStatus {
+    pub fn code(&self) -> Code {
+        Code::from_i32(self.code)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.code() == Code::Ok
+    }
+}
+
+impl BulkWriteReport {
+    pub fn written(&self) -> usize {
+        self.statuses.iter().filter(|status| status.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &WriteStatus> {
+        self.statuses.iter().filter(|status| !status.is_ok())
+    }
+}
+
+/// Seeds the collection with non-atomic `BatchWrite` requests.
+///
+/// Unlike [`seed_collection_with_options`](super::seed::seed_collection_with_options)
+/// every write succeeds or fails on its own. Documents are written after all documents closer
+/// to the root, up to [`SeedOptions::concurrency`] chunks of a level are sent at the same time
+/// and the rate of writes follows the ramp-up rule of Firestore. Writes failed
+/// with a transient error are retried one by one with the backoff of the connection's
+/// [`RetryPolicy`](super::retry::RetryPolicy), and the status of each document is reported.
+///
+/// A request failed as a whole is only repeated for writes without transforms and preconditions,
+/// as it might have been applied. With [`SeedOptions::undo_log`] set the prior state of the
+/// documents is saved before anything is written. With [`SeedOptions::regenerate_ids`] set
+/// the subtrees of the documents which failed are reported as failed without being sent,
+/// as they would end up under a document the seed did not create.
+/// The writes are not committed in batches, so [`SeedOptions::journal`] and
+/// [`SeedOptions::resume`] are rejected.
+pub async fn bulk_seed_collection(
+    conn: FirestoreConnection,
+    collection: &CollectionData,
+    parent_document_path: &str,
+    options: &SeedOptions,
+) -> Result<BulkWriteReport, SeedError> {
+    if !validate_document_path(parent_document_path) {
+        return Err(SeedError::InvalidPath);
+    }
+    if options.journal.is_some() || options.resume {
+        return Err(SeedError::FirestoreClientError(
+            "A bulk seed can't be recorded in a journal, its writes are not committed in batches"
+                .into(),
+        ));
+    }
+    let parent_path = parent_document_full_path(&conn.1, parent_document_path);
+    let (writes, id_mapping, options) = generate_seed_writes(collection, &parent_path, options);
+    let writes = writes
+        .into_iter()
+        .map(|write| apply_options(write, &options))
+        .collect::<Vec<_>>();
+    if let Some(undo_log) = &options.undo_log {
+        save_prior_state(conn.clone(), &writes, undo_log)
+            .await
+            .map_err(SeedError::FirestoreClientError)?;
+    }
+    let mut report = bulk_write(conn, writes, &options).await;
+    report.id_mapping = id_mapping;
+    Ok(report)
+}
+
+async fn save_prior_state(
+    conn: FirestoreConnection,
+    writes: &[Write],
+    path: &str,
+) -> Result<(), BoxError> {
+    let prior_state = capture_prior_state(conn, writes).await?;
+    fs::write(path, serde_json::to_string_pretty(&prior_state)?)?;
+    Ok(())
+}
+
+pub(crate) async fn bulk_write(
+    conn: FirestoreConnection,
+    writes: Vec<Write>,
+    options: &SeedOptions,
+) -> BulkWriteReport {
+    let database_path = conn.1.trim_end_matches("/documents").to_string();
+    let limiter = RampUpLimiter::new();
+    let mut report = BulkWriteReport::default();
+    let mut failed_names = HashSet::new();
+    for level in ordered_batches(&writes) {
+        let mut chunks = Vec::new();
+        for chunk in level {
+            let chunk = if options.regenerate_ids {
+                let (chunk, orphaned) = chunk.into_iter().partition::<Vec<_>, _>(|write| {
+                    !has_skipped_parent(write_document_name(write), &failed_names)
+                });
+                report
+                    .statuses
+                    .extend(orphaned.iter().map(|write| WriteStatus {
+                        path: write_document_name(write).to_string(),
+                        code: Code::FailedPrecondition as i32,
+                        message: "One of the parent documents was not written".to_string(),
+                        attempts: 0,
+                    }));
+                chunk
+            } else {
+                chunk
+            };
+            if !chunk.is_empty() {
+                chunks.push(chunk);
+            }
+        }
+        let chunk_results = stream::iter(chunks)
+            .map(|chunk| {
+                let conn = conn.clone();
+                let database_path = database_path.clone();
+                let limiter = limiter.clone();
+                async move {
+                    limiter.acquire(chunk.len()).await;
+                    write_chunk(conn, database_path, chunk).await
+                }
+            })
+            .buffered(options.concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await;
+        for (statuses, retries) in chunk_results {
+            report.statuses.extend(statuses);
+            report.retries += retries;
+        }
+        failed_names.extend(report.failed().map(|status| status.path.clone()));
+    }
+
+    // the levels group the documents by depth, the report keeps the order of the snapshot
+    let positions = writes
+        .iter()
+        .enumerate()
+        .map(|(position, write)| (write_document_name(write), position))
+        .collect::<HashMap<_, _>>();
+    report
+        .statuses
+        .sort_by_key(|status| positions.get(status.path.as_str()).copied());
+    report
+}
+
+/// Writes the chunk and retries the failed writes, returns their statuses and the number of retries.
+async fn write_chunk(
+    conn: FirestoreConnection,
+    database_path: String,
+    writes: Vec<Write>,
+) -> (Vec<WriteStatus>, usize) {
+    let policy = conn.2.policy().clone();
+    let idempotent = writes.iter().all(is_idempotent);
+    let mut retries = 0;
+    let mut chunk_attempts = 1;
+    // the outcome of the writes of a failed request is unknown
+    let (chunk_statuses, outcome_unknown) = loop {
+        match batch_write(conn.clone(), &database_path, writes.clone()).await {
+            Ok(statuses) => break (statuses, false),
+            Err(error)
+                if idempotent
+                    && chunk_attempts < policy.max_attempts
+                    && policy.is_retriable(&error) =>
+            {
+                sleep(policy.backoff_with_jitter(chunk_attempts)).await;
+                chunk_attempts += 1;
+                retries += 1;
+            }
+            Err(error) => break (vec![request_status(&error); writes.len()], true),
+        }
+    };
+
+    let mut statuses = Vec::new();
+    for (write, mut status) in writes.into_iter().zip(chunk_statuses) {
+        let mut attempts = chunk_attempts;
+        let mut outcome_unknown = outcome_unknown;
+        while attempts < policy.max_attempts
+            && (!outcome_unknown || is_idempotent(&write))
+            && policy.is_retriable_code(Code::from_i32(status.code))
+        {
+            sleep(policy.backoff_with_jitter(attempts)).await;
+            attempts += 1;
+            retries += 1;
+            match batch_write(conn.clone(), &database_path, vec![write.clone()]).await {
+                Ok(mut write_statuses) => {
+                    status = write_statuses.remove(0);
+                    outcome_unknown = false;
+                }
+                Err(error) => {
+                    status = request_status(&error);
+                    outcome_unknown = true;
+                }
+            }
+        }
+        statuses.push(WriteStatus {
+            path: write_document_name(&write).to_string(),
+            code: status.code,
+            message: status.message,
+            attempts,
+        });
+    }
+    (statuses, retries)
+}
+
+/// Sends the writes in a single `BatchWrite` request and returns the status of each of them.
+///
+/// Writes the response has no status for are reported as failed.
+async fn batch_write(
+    conn: FirestoreConnection,
+    database_path: &str,
+    writes: Vec<Write>,
+) -> Result<Vec<Status>, tonic::Status> {
+    let FirestoreConnection(mut client, _base_path, _) = conn;
+    let writes_count = writes.len();
+    let request = BatchWriteRequest {
+        database: database_path.to_string(),
+        writes,
+        labels: Default::default(),
+    };
+    let mut statuses = client.batch_write(request).await?.into_inner().status;
+    statuses.resize(
+        writes_count,
+        Status {
+            code: Code::Unknown as i32,
+            message: "The response has no status for the write".to_string(),
+            details: vec![],
+        },
+    );
+    Ok(statuses)
+}
+
+/// Status of a write sent in a request which failed as a whole.
+fn request_status(error: &tonic::Status) -> Status {
+    Status {
+        code: error.code() as i32,
+        message: error.message().to_string(),
+        details: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_counts() {
+        let status = |path: &str, code: Code| WriteStatus {
+            path: path.to_string(),
+            code: code as i32,
+            message: String::new(),
+            attempts: 1,
+        };
+        let report = BulkWriteReport {
+            statuses: vec![
+                status("a", Code::Ok),
+                status("b", Code::AlreadyExists),
+                status("c", Code::Ok),
+            ],
+            ..BulkWriteReport::default()
+        };
+
+        assert_eq!(report.written(), 2);
+        assert_eq!(
+            report.failed().map(|s| s.path.as_str()).collect::<Vec<_>>(),
+            vec!["b"]
+        );
+    }
+}
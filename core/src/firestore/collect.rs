@@ -1,137 +1,539 @@
-use std::iter::FromIterator;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Write,
+    sync::{Arc, Mutex},
+};
 
-use firestore_grpc::v1::{Document, ListCollectionIdsRequest, ListDocumentsRequest};
+use firestore_grpc::v1::{
+    batch_get_documents_response, run_query_request::QueryType, BatchGetDocumentsRequest, Document,
+    DocumentMask, ListCollectionIdsRequest, ListDocumentsRequest, RunQueryRequest, StructuredQuery,
+};
+use serde::{Deserialize, Serialize};
 
-use super::{BoxError, FirestoreConnection};
-use async_recursion::async_recursion;
-use futures::{stream::FuturesUnordered, StreamExt};
+use super::{transform::DocumentTransformer, BoxError, FirestoreConnection};
+use futures::future::{try_join_all, BoxFuture, FutureExt};
 
 use super::type_mapping::*;
 
-#[async_recursion]
+/// Collects the documents of the collection together with all of their subcollections.
+///
+/// Fails when any of the documents or subcollections can't be read,
+/// instead of leaving them out of the snapshot.
 pub async fn collect_collection(
     conn: FirestoreConnection,
     full_path: String,
 ) -> Result<CollectionData, BoxError> {
-    let conn_clone = conn.clone();
-    let FirestoreConnection(mut client, _base_path) = conn;
+    collect_collection_with_context(conn, full_path, CollectContext::default()).await
+}
+
+/// Collects the collection passing every document through the transformer before its
+/// subcollections are collected, so that the subtrees of dropped documents are not collected at all.
+///
+/// The transformer receives the documents without their subcollections.
+pub(crate) async fn collect_collection_with_transformer(
+    conn: FirestoreConnection,
+    full_path: String,
+    transformer: &dyn DocumentTransformer,
+) -> Result<CollectionData, BoxError> {
+    let context = CollectContext {
+        checkpoint: None,
+        transformer: Some(transformer),
+    };
+    collect_collection_with_context(conn, full_path, context).await
+}
+
+/// Collects the collection appending every collected page of documents to the checkpoint file.
+///
+/// When the checkpoint file exists, collections already finished by an interrupted run are
+/// taken from it and partially listed collections continue from the saved page token.
+/// The checkpoint file is removed once the whole collection is collected.
+pub async fn collect_collection_resumable(
+    conn: FirestoreConnection,
+    full_path: String,
+    checkpoint_path: &str,
+) -> Result<CollectionData, BoxError> {
+    collect_resumable(conn, full_path, checkpoint_path, None).await
+}
+
+/// Collects the collection like [`collect_collection_resumable`], passing every document
+/// through the transformer before the page is appended to the checkpoint file.
+pub(crate) async fn collect_collection_resumable_with_transformer(
+    conn: FirestoreConnection,
+    full_path: String,
+    checkpoint_path: &str,
+    transformer: &dyn DocumentTransformer,
+) -> Result<CollectionData, BoxError> {
+    collect_resumable(conn, full_path, checkpoint_path, Some(transformer)).await
+}
+
+async fn collect_resumable(
+    conn: FirestoreConnection,
+    full_path: String,
+    checkpoint_path: &str,
+    transformer: Option<&dyn DocumentTransformer>,
+) -> Result<CollectionData, BoxError> {
+    let context = CollectContext {
+        checkpoint: Some(ExportCheckpoint::open(checkpoint_path)?),
+        transformer,
+    };
+    let collection = collect_collection_with_context(conn, full_path, context).await?;
+    fs::remove_file(checkpoint_path)?;
+    Ok(collection)
+}
+
+/// Progress of an export replayed from a checkpoint file.
+#[derive(Debug, Default)]
+struct ExportState {
+    /// Progress of started collections keyed by their full paths.
+    collections: BTreeMap<String, CollectionProgress>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CollectionProgress {
+    /// Token of the next page of documents, empty before the first page.
+    page_token: String,
+    /// Documents of the already listed pages, together with their subcollections.
+    documents: Vec<DocumentData>,
+    finished: bool,
+}
+
+/// Line of a checkpoint file recording a collected page of documents.
+#[derive(Debug, Serialize, Deserialize)]
+struct PageRecord {
+    /// Full path of the collection.
+    collection: String,
+    /// Documents of the page, together with their subcollections.
+    documents: Vec<DocumentData>,
+    /// Token of the next page, empty after the last page.
+    next_page_token: String,
+}
+
+#[derive(Clone)]
+struct ExportCheckpoint {
+    file: Arc<Mutex<fs::File>>,
+    state: Arc<ExportState>,
+}
 
-    let (parent_path, collection_id) = split_path(&full_path);
+impl ExportCheckpoint {
+    /// Opens the checkpoint file and replays the pages recorded in it.
+    ///
+    /// The last line is dropped when it can't be parsed, it was cut off by the interruption.
+    fn open(path: &str) -> Result<Self, BoxError> {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+        let mut state = ExportState::default();
+        let mut replayed_len = 0;
+        for (index, line) in lines.iter().enumerate() {
+            let record = match serde_json::from_str::<PageRecord>(line) {
+                Ok(record) => record,
+                Err(_) if index + 1 == lines.len() => break,
+                Err(error) => return Err(error.into()),
+            };
+            replayed_len += line.len();
+            // the documents of the page carry their subtrees, the progress of the subcollections is not needed anymore
+            let prefix = format!("{}/", record.collection);
+            state
+                .collections
+                .retain(|path, _| !path.starts_with(&prefix));
+            let progress = state.collections.entry(record.collection).or_default();
+            progress.documents.extend(record.documents);
+            progress.finished = record.next_page_token.is_empty();
+            progress.page_token = record.next_page_token;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.set_len(replayed_len as u64)?;
+        Ok(ExportCheckpoint {
+            file: Arc::new(Mutex::new(file)),
+            state: Arc::new(state),
+        })
+    }
+
+    fn progress(&self, collection_path: &str) -> CollectionProgress {
+        self.state
+            .collections
+            .get(collection_path)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Appends the page of the collection to the checkpoint file.
+    ///
+    /// A page is recorded once all of its documents are collected together with their subtrees.
+    fn record_page(
+        &self,
+        collection_path: &str,
+        documents: &[DocumentData],
+        next_page_token: &str,
+    ) -> Result<(), BoxError> {
+        let record = PageRecord {
+            collection: collection_path.to_string(),
+            documents: documents.to_vec(),
+            next_page_token: next_page_token.to_string(),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// State shared by all collections of a single export.
+#[derive(Clone, Default)]
+struct CollectContext<'a> {
+    checkpoint: Option<ExportCheckpoint>,
+    transformer: Option<&'a dyn DocumentTransformer>,
+}
+
+// boxed by hand, as the recursion borrows the transformer of the context
+fn collect_collection_with_context<'a>(
+    conn: FirestoreConnection,
+    full_path: String,
+    context: CollectContext<'a>,
+) -> BoxFuture<'a, Result<CollectionData, BoxError>> {
+    async move {
+        let (_, collection_id) = split_path(&full_path);
+        let mut progress = context
+            .checkpoint
+            .as_ref()
+            .map(|checkpoint| checkpoint.progress(&full_path))
+            .unwrap_or_default();
 
+        while !progress.finished {
+            let (documents, next_page_token) = collect_page(
+                conn.clone(),
+                &full_path,
+                progress.page_token.clone(),
+                context.clone(),
+            )
+            .await?;
+            if let Some(checkpoint) = &context.checkpoint {
+                checkpoint.record_page(&full_path, &documents, &next_page_token)?;
+            }
+            progress.documents.extend(documents);
+            progress.page_token = next_page_token;
+            progress.finished = progress.page_token.is_empty();
+        }
+
+        let collection_data = CollectionData {
+            id: collection_id,
+            documents: progress.documents,
+        };
+        Ok(collection_data)
+    }
+    .boxed()
+}
+
+/// Collects a single page of documents of the collection together with their subcollections.
+///
+/// Returns the documents and the token of the next page, which is empty after the last page.
+pub(crate) async fn collect_collection_page(
+    conn: FirestoreConnection,
+    full_path: &str,
+    page_token: String,
+) -> Result<(Vec<DocumentData>, String), BoxError> {
+    collect_page(conn, full_path, page_token, CollectContext::default()).await
+}
+
+async fn collect_page(
+    conn: FirestoreConnection,
+    full_path: &str,
+    page_token: String,
+    context: CollectContext<'_>,
+) -> Result<(Vec<DocumentData>, String), BoxError> {
+    let (documents, next_page_token) =
+        list_documents_page(conn.clone(), full_path, page_token, true).await?;
+    let documents = try_join_all(
+        documents
+            .into_iter()
+            .map(|item| firestore_doc_to_document_data(conn.clone(), item, context.clone())),
+    )
+    .await?;
+    Ok((documents.into_iter().flatten().collect(), next_page_token))
+}
+
+/// Lists a single page of documents of the collection, without their subcollections.
+///
+/// With `show_missing` set documents which only have subcollections are listed as well.
+/// Returns the documents and the token of the next page, which is empty after the last page.
+pub(crate) async fn list_documents_page(
+    conn: FirestoreConnection,
+    full_path: &str,
+    page_token: String,
+    show_missing: bool,
+) -> Result<(Vec<Document>, String), BoxError> {
+    let FirestoreConnection(client, _base_path, retrier) = conn;
+    let (parent_path, collection_id) = split_path(full_path);
     let request = ListDocumentsRequest {
         parent: parent_path.trim_matches('/').to_string(),
-        collection_id: collection_id.to_string(),
+        collection_id,
         page_size: 400,
-        page_token: "".to_string(),
+        page_token,
         order_by: "".to_string(),
         mask: None,
-        show_missing: true,
+        show_missing,
         consistency_selector: None,
     };
 
-    let result = client.list_documents(request).await?;
+    let result = retrier
+        .call(|| {
+            let mut client = client.clone();
+            let request = request.clone();
+            async move { client.list_documents(request).await }
+        })
+        .await?;
     let result = result.into_inner();
-
-    let collection_path = full_path;
-
-    let document_futures =
-        FuturesUnordered::from_iter(result.documents.into_iter().map(|item| {
-            firestore_doc_to_document_data(conn_clone.clone(), item, &collection_path)
-        }));
-
-    let documents_results = document_futures
-        .collect::<Vec<Result<DocumentData, BoxError>>>()
-        .await;
-    let (_, collection_id) = split_path(&collection_path);
-
-    let documents: Vec<DocumentData> = documents_results
-        .into_iter()
-        .filter_map(|v| v.ok())
-        .collect();
-    let collection_data = CollectionData {
-        id: collection_id,
-        documents: documents,
-    };
-    Ok(collection_data)
+    Ok((result.documents, result.next_page_token))
 }
 
-#[async_recursion]
-async fn firestore_doc_to_document_data(
+/// Converts the document into its data together with its subcollections,
+/// or into the documents returned by the transformer of the context.
+async fn firestore_doc_to_document_data<'a>(
     conn: FirestoreConnection,
     item: Document,
-    _document_parent_path: &str,
-) -> Result<DocumentData, BoxError> {
+    context: CollectContext<'a>,
+) -> Result<Vec<DocumentData>, BoxError> {
     let (_, id) = split_path(&item.name);
-    let subcollections = collect_document_collections(conn, &item.name).await?;
-    Ok(DocumentData {
-        id: id,
+    let document = DocumentData {
+        id,
         data: item
             .fields
             .into_iter()
-            .filter_map(|(key, val)| {
-                if val.value_type.is_none() {
-                    return None;
-                } else {
-                    Some((key, val.value_type.unwrap()))
-                }
-            })
+            .filter_map(|(key, val)| val.value_type.map(|value_type| (key, value_type)))
             .map(|(key, raw_value)| {
                 let converted = from_firestore_value(raw_value);
                 (key, converted)
             })
             .collect(),
-        subcollections: subcollections,
-    })
+        subcollections: None,
+    };
+    let documents = match context.transformer {
+        Some(transformer) => {
+            let relative_path = item
+                .name
+                .strip_prefix(conn.1.as_str())
+                .unwrap_or(&item.name)
+                .trim_matches('/');
+            transformer.transform(relative_path, document)?
+        }
+        None => vec![document],
+    };
+    if documents.is_empty() {
+        return Ok(documents);
+    }
+    let subcollections = collect_subcollections(conn, &item.name, context).await?;
+    Ok(documents
+        .into_iter()
+        .map(|mut document| {
+            if document.subcollections.is_none() {
+                document.subcollections = subcollections.clone();
+            }
+            document
+        })
+        .collect())
+}
+
+/// Converts the listed or fetched document into its data together with its subcollections,
+/// or into the documents returned by the transformer together with the subcollections.
+pub(crate) async fn collect_document(
+    conn: FirestoreConnection,
+    document: Document,
+    transformer: Option<&dyn DocumentTransformer>,
+) -> Result<Vec<DocumentData>, BoxError> {
+    let context = CollectContext {
+        checkpoint: None,
+        transformer,
+    };
+    firestore_doc_to_document_data(conn, document, context).await
 }
 
-#[async_recursion]
-async fn collect_document_collections(
+pub(crate) async fn collect_document_collections(
     conn: FirestoreConnection,
     doc_path: &str,
 ) -> Result<Option<Vec<CollectionData>>, BoxError> {
-    let conn_clone = conn.clone();
-    let FirestoreConnection(mut client, _base_path) = conn;
+    collect_subcollections(conn, doc_path, CollectContext::default()).await
+}
+
+async fn collect_subcollections<'a>(
+    conn: FirestoreConnection,
+    doc_path: &str,
+    context: CollectContext<'a>,
+) -> Result<Option<Vec<CollectionData>>, BoxError> {
     let document_full_path = doc_path.to_string();
-    println!(
-        "collect_document_collections: document_full_path = {}",
-        document_full_path
-    );
-    let request = ListCollectionIdsRequest {
-        parent: document_full_path.clone(),
-        page_size: 400,
-        page_token: "".to_string(),
-    };
-    let response = client.list_collection_ids(request).await?;
-    let collection_ids = response.get_ref();
-    println!(
-        "collect_document_collections: received {} subcollections for {}",
-        collection_ids.collection_ids.len(),
-        document_full_path
-    );
-
-    let futures = FuturesUnordered::new();
-    for id in &collection_ids.collection_ids {
-        let collection_path = format!("{}/{}", document_full_path, id);
-        futures.push(collect_collection(conn_clone.clone(), collection_path));
-    }
+    let collection_ids = list_collection_ids(conn.clone(), &document_full_path).await?;
 
-    let subcollections = futures
-        .collect::<Vec<Result<CollectionData, BoxError>>>()
-        .await;
-    println!(
-        "collect_document_collections {} -> {:?} subcollections",
-        document_full_path, subcollections
-    );
+    let subcollections = try_join_all(collection_ids.iter().map(|id| {
+        let collection_path = format!("{}/{}", document_full_path, id);
+        collect_collection_with_context(conn.clone(), collection_path, context.clone())
+    }))
+    .await?;
     if subcollections.is_empty() {
         Ok(None)
     } else {
-        Ok(Some(
-            subcollections
-                .into_iter()
-                .filter_map(|val| val.ok())
-                .collect(),
-        ))
+        Ok(Some(subcollections))
+    }
+}
+
+/// Lists IDs of all collections of the document with the given full name.
+pub(crate) async fn list_collection_ids(
+    conn: FirestoreConnection,
+    document_full_path: &str,
+) -> Result<Vec<String>, BoxError> {
+    let FirestoreConnection(client, _base_path, retrier) = conn;
+    let mut collection_ids = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let request = ListCollectionIdsRequest {
+            parent: document_full_path.to_string(),
+            page_size: 400,
+            page_token: page_token.clone(),
+        };
+        let response = retrier
+            .call(|| {
+                let mut client = client.clone();
+                let request = request.clone();
+                async move { client.list_collection_ids(request).await }
+            })
+            .await?
+            .into_inner();
+        collection_ids.extend(response.collection_ids);
+        page_token = response.next_page_token;
+        if page_token.is_empty() {
+            return Ok(collection_ids);
+        }
+    }
+}
+
+/// Lists IDs of all documents of the collection, including missing documents which only have subcollections.
+///
+/// Fields of the documents are not fetched.
+pub(crate) async fn list_document_ids(
+    conn: FirestoreConnection,
+    parent_full_path: &str,
+    collection_id: &str,
+) -> Result<Vec<String>, BoxError> {
+    let FirestoreConnection(client, _base_path, retrier) = conn;
+    let mut document_ids = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let request = ListDocumentsRequest {
+            parent: parent_full_path.to_string(),
+            collection_id: collection_id.to_string(),
+            page_size: 400,
+            page_token: page_token.clone(),
+            order_by: "".to_string(),
+            mask: Some(DocumentMask {
+                field_paths: vec![],
+            }),
+            show_missing: true,
+            consistency_selector: None,
+        };
+        let response = retrier
+            .call(|| {
+                let mut client = client.clone();
+                let request = request.clone();
+                async move { client.list_documents(request).await }
+            })
+            .await?
+            .into_inner();
+        document_ids.extend(
+            response
+                .documents
+                .iter()
+                .map(|document| split_path(&document.name).1),
+        );
+        page_token = response.next_page_token;
+        if page_token.is_empty() {
+            return Ok(document_ids);
+        }
+    }
+}
+
+/// Maximum number of documents fetched by a single request.
+const FETCH_CHUNK_SIZE: usize = 100;
+
+/// Fetches the documents with the given full names.
+///
+/// Only existing documents are present in the returned map, keyed by their names.
+/// The names are requested in chunks of at most [`FETCH_CHUNK_SIZE`] documents.
+pub async fn get_documents(
+    conn: FirestoreConnection,
+    document_names: Vec<String>,
+) -> Result<HashMap<String, Document>, BoxError> {
+    let mut found = HashMap::new();
+    for chunk in document_names.chunks(FETCH_CHUNK_SIZE) {
+        found.extend(get_documents_chunk(conn.clone(), chunk.to_vec()).await?);
     }
+    Ok(found)
+}
+
+async fn get_documents_chunk(
+    conn: FirestoreConnection,
+    document_names: Vec<String>,
+) -> Result<HashMap<String, Document>, BoxError> {
+    let FirestoreConnection(client, base_path, retrier) = conn;
+    let request = BatchGetDocumentsRequest {
+        database: base_path.trim_end_matches("/documents").to_string(),
+        documents: document_names,
+        mask: None,
+        consistency_selector: None,
+    };
+    let found = retrier
+        .call(|| {
+            let mut client = client.clone();
+            let request = request.clone();
+            async move {
+                let mut stream = client.batch_get_documents(request).await?.into_inner();
+                let mut found = HashMap::new();
+                while let Some(response) = stream.message().await? {
+                    if let Some(batch_get_documents_response::Result::Found(document)) =
+                        response.result
+                    {
+                        found.insert(document.name.clone(), document);
+                    }
+                }
+                Ok(found)
+            }
+        })
+        .await?;
+    Ok(found)
+}
+
+/// Runs the query on the collections of the parent document with the given full name
+/// and returns the matching documents. Root collections are queried with the documents path
+/// of the connection as the parent.
+///
+/// A query failing with a transient error is run again as a whole,
+/// even when it fails while the results are streamed.
+pub async fn query_documents(
+    conn: FirestoreConnection,
+    parent_full_path: &str,
+    query: StructuredQuery,
+) -> Result<Vec<Document>, BoxError> {
+    let FirestoreConnection(client, _base_path, retrier) = conn;
+    let request = RunQueryRequest {
+        parent: parent_full_path.to_string(),
+        query_type: Some(QueryType::StructuredQuery(query)),
+        consistency_selector: None,
+    };
+    let documents = retrier
+        .call(|| {
+            let mut client = client.clone();
+            let request = request.clone();
+            async move {
+                let mut stream = client.run_query(request).await?.into_inner();
+                let mut documents = Vec::new();
+                while let Some(response) = stream.message().await? {
+                    documents.extend(response.document);
+                }
+                Ok(documents)
+            }
+        })
+        .await?;
+    Ok(documents)
 }
 
 type PathSegments = (String, String);
@@ -181,4 +583,52 @@ mod tests {
         assert_eq!(base_path, "/base/id");
         assert_eq!(collection_id, "collection");
     }
+
+    fn document(id: &str) -> DocumentData {
+        DocumentData {
+            id: id.into(),
+            data: HashMap::new(),
+            subcollections: None,
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_replay() {
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let checkpoint = ExportCheckpoint::open(path).unwrap();
+        checkpoint
+            .record_page("users", &[document("a")], "next")
+            .unwrap();
+        checkpoint
+            .record_page("users/b/posts", &[document("p")], "")
+            .unwrap();
+        checkpoint
+            .record_page("users", &[document("b")], "last")
+            .unwrap();
+        checkpoint
+            .record_page("users/c/posts", &[document("q")], "more")
+            .unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(b"{\"collection\":")
+            .unwrap();
+
+        let replayed = ExportCheckpoint::open(path).unwrap();
+        replayed.record_page("users/c/posts", &[], "").unwrap();
+        let content = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(content.lines().count(), 5);
+        assert!(content.ends_with("\"next_page_token\":\"\"}\n"));
+        let users = replayed.progress("users");
+        assert_eq!(users.documents, vec![document("a"), document("b")]);
+        assert_eq!(users.page_token, "last");
+        assert!(!users.finished);
+        assert!(replayed.progress("users/b/posts").documents.is_empty());
+        assert_eq!(replayed.progress("users/c/posts").page_token, "more");
+    }
 }
@@ -0,0 +1,180 @@
+use super::{
+    collect::{collect_collection, collect_collection_page},
+    seed::{
+        commit_writes_with_limiter, generate_seed_writes, parent_document_full_path,
+        validate_document_path, CollectionData, PathRemap, SeedError, SeedOptions, SeedReport,
+    },
+    throttle::RampUpLimiter,
+    FirestoreConnection,
+};
+
+/// Copies the collection from the source connection under the parent document of the destination connection.
+///
+/// Documents are read page by page and every page is written before the next one is read,
+/// so the collection is never held in memory as a whole, unless the IDs are regenerated.
+/// Document paths and references are moved from the source collection to the destination
+/// (including the project and the database). The move takes precedence over the prefixes of
+/// [`SeedOptions::remap`], which only rewrite references pointing outside of the source collection.
+/// The journal and the undo log of the options are not supported and ignored.
+/// The ramp-up of the write rate carries over from one page to the next.
+pub async fn copy_collection(
+    source: FirestoreConnection,
+    source_collection_path: &str,
+    destination: FirestoreConnection,
+    destination_parent_path: &str,
+    options: &SeedOptions,
+) -> Result<SeedReport, SeedError> {
+    let source_collection_path = source_collection_path.trim_matches('/');
+    if !validate_document_path(destination_parent_path)
+        || source_collection_path.split('/').count().is_multiple_of(2)
+    {
+        return Err(SeedError::InvalidPath);
+    }
+    let destination = destination.scoped_retries();
+    let source_full_path = format!("{}/{}", source.1, source_collection_path);
+    let (source_parent_path, collection_id) = source_full_path.rsplit_once('/').unwrap();
+    let destination_collection_path = format!(
+        "{}/{}",
+        parent_document_full_path(&destination.1, destination_parent_path),
+        collection_id
+    );
+    let options = SeedOptions {
+        remap: copy_remap(
+            &options.remap,
+            &source_full_path,
+            &destination_collection_path,
+        ),
+        journal: None,
+        resume: false,
+        undo_log: None,
+        ..options.clone()
+    };
+    let database_path = destination.1.trim_end_matches("/documents").to_string();
+    // names keep the relative path of the source, the move to the destination is one of the prefix rules
+    let seed_parent_path = source_parent_path.replacen(source.1.as_str(), &destination.1, 1);
+
+    let limiter = RampUpLimiter::new();
+    let mut report = SeedReport::default();
+    let page_writes = |documents| {
+        let page = CollectionData {
+            id: collection_id.to_string(),
+            documents,
+        };
+        generate_seed_writes(&page, &seed_parent_path, &options)
+    };
+
+    if options.regenerate_ids {
+        // references between pages can only be rewritten with the mapping of the whole collection
+        let collection = collect_collection(source, source_full_path.clone())
+            .await
+            .map_err(SeedError::FirestoreClientError)?;
+        let (writes, id_mapping, page_options) = page_writes(collection.documents);
+        let page_report = commit_writes_with_limiter(
+            destination.clone(),
+            &writes,
+            &database_path,
+            &page_options,
+            limiter.clone(),
+        )
+        .await
+        .map_err(SeedError::FirestoreClientError)?;
+        report.written = page_report.written;
+        report.skipped = page_report.skipped;
+        report.id_mapping = id_mapping;
+    } else {
+        let mut page_token = String::new();
+        loop {
+            let (documents, next_page_token) =
+                collect_collection_page(source.clone(), &source_full_path, page_token)
+                    .await
+                    .map_err(SeedError::FirestoreClientError)?;
+            let (writes, id_mapping, page_options) = page_writes(documents);
+            let page_report = commit_writes_with_limiter(
+                destination.clone(),
+                &writes,
+                &database_path,
+                &page_options,
+                limiter.clone(),
+            )
+            .await
+            .map_err(SeedError::FirestoreClientError)?;
+            report.written += page_report.written;
+            report.skipped.extend(page_report.skipped);
+            report.id_mapping.extend(id_mapping);
+            if next_page_token.is_empty() {
+                break;
+            }
+            page_token = next_page_token;
+        }
+    }
+    report.retries = destination.2.retries();
+    Ok(report)
+}
+
+/// Extends the remapping rules with the move of the source collection to the destination,
+/// which is the first rule so that the copied documents always end up in the destination.
+fn copy_remap(remap: &PathRemap, source_path: &str, destination_path: &str) -> PathRemap {
+    let relative = |path: &str| path.splitn(6, '/').nth(5).unwrap_or_default().to_string();
+    let segment = |path: &str, index: usize| path.split('/').nth(index).map(String::from);
+    let mut prefixes = vec![(relative(source_path), relative(destination_path))];
+    prefixes.extend(remap.prefixes.iter().cloned());
+    PathRemap {
+        project: remap
+            .project
+            .clone()
+            .or_else(|| segment(destination_path, 1)),
+        database: remap
+            .database
+            .clone()
+            .or_else(|| segment(destination_path, 3)),
+        prefixes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_remap() {
+        let remap = copy_remap(
+            &PathRemap::default(),
+            "projects/source/databases/(default)/documents/orders",
+            "projects/target/databases/(default)/documents/users/u1/orders",
+        );
+
+        assert_eq!(
+            remap.apply("projects/source/databases/(default)/documents/orders/o1/items/i1"),
+            "projects/target/databases/(default)/documents/users/u1/orders/o1/items/i1"
+        );
+        assert_eq!(
+            remap.apply("projects/source/databases/(default)/documents/products/p1"),
+            "projects/target/databases/(default)/documents/products/p1"
+        );
+    }
+
+    #[test]
+    fn test_copy_remap_precedes_user_prefixes() {
+        let user_remap = PathRemap {
+            prefixes: vec![
+                ("orders".into(), "archive/orders".into()),
+                ("products".into(), "catalog".into()),
+            ],
+            ..PathRemap::default()
+        };
+        let remap = copy_remap(
+            &user_remap,
+            "projects/source/databases/(default)/documents/orders",
+            "projects/target/databases/(default)/documents/users/u1/orders",
+        );
+
+        assert_eq!(
+            remap.apply_prefixes("projects/source/databases/(default)/documents/orders/o1"),
+            "projects/source/databases/(default)/documents/users/u1/orders/o1"
+        );
+        assert_eq!(
+            remap.apply("projects/source/databases/(default)/documents/products/p1"),
+            "projects/target/databases/(default)/documents/catalog/p1"
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use serde::Serialize;
+
+use super::{
+    collect::{collect_collection, collect_document_collections},
+    seed::{
+        commit_transaction, delete_write, document_names_for_collection, SeedOptions,
+        BATCH_UPDATE_MAX_SIZE,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// Progress of a deletion, reported after every committed batch.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DeleteProgress {
+    pub deleted: usize,
+    pub total: usize,
+}
+
+/// Lists full names of all documents in the subtree found on the path.
+///
+/// The path is relative to the database and points either to a collection or to a document.
+/// Documents are listed parents first.
+pub async fn list_subtree(conn: FirestoreConnection, path: &str) -> Result<Vec<String>, BoxError> {
+    let trimmed_path = path.trim_matches('/');
+    if trimmed_path.is_empty() {
+        return Err("Deleting the whole database is not supported. Provide a path to a collection or a document.".into());
+    }
+    let full_path = format!("{}/{}", conn.1, trimmed_path);
+    let segments_count = trimmed_path.split('/').count();
+    if segments_count % 2 == 1 {
+        let (parent_path, _) = full_path.rsplit_once('/').unwrap();
+        let parent_path = parent_path.to_string();
+        let collection = collect_collection(conn, full_path).await?;
+        Ok(document_names_for_collection(&collection, &parent_path))
+    } else {
+        let subcollections = collect_document_collections(conn, &full_path).await?;
+        let mut names = vec![full_path.clone()];
+        for subcollection in subcollections.unwrap_or_default() {
+            names.extend(document_names_for_collection(&subcollection, &full_path));
+        }
+        Ok(names)
+    }
+}
+
+/// Deletes the collection or the document found on the path together with all its subcollections.
+///
+/// Documents are removed in batches, children before their parents,
+/// and `on_progress` is called after every batch.
+/// Returns the number of deleted documents.
+pub async fn delete_recursive<F>(
+    conn: FirestoreConnection,
+    path: &str,
+    on_progress: F,
+) -> Result<usize, BoxError>
+where
+    F: Fn(DeleteProgress) + Send + Sync,
+{
+    let mut names = list_subtree(conn.clone(), path).await?;
+    names.reverse();
+    delete_documents(conn, names, on_progress).await
+}
+
+/// Deletes the documents with the given full names in batches.
+///
+/// Subcollections of the documents are not affected.
+pub(crate) async fn delete_documents<F>(
+    conn: FirestoreConnection,
+    document_names: Vec<String>,
+    on_progress: F,
+) -> Result<usize, BoxError>
+where
+    F: Fn(DeleteProgress) + Send + Sync,
+{
+    let database_path = conn.1.trim_end_matches("/documents").to_string();
+    let writes = document_names
+        .into_iter()
+        .map(delete_write)
+        .collect::<Vec<_>>();
+
+    let mut progress = DeleteProgress {
+        deleted: 0,
+        total: writes.len(),
+    };
+    for batch in writes.chunks(BATCH_UPDATE_MAX_SIZE) {
+        commit_transaction(
+            conn.clone(),
+            Vec::from(batch),
+            &database_path,
+            &SeedOptions::default(),
+        )
+        .await?;
+        progress.deleted += batch.len();
+        on_progress(progress);
+    }
+    Ok(writes.len())
+}
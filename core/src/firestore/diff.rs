@@ -0,0 +1,335 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+};
+
+use serde::Serialize;
+use serde_json::json;
+
+use super::{
+    collect::collect_collection,
+    seed::{
+        parent_document_full_path, validate_document_path, CollectionData, SeedError, ValueType,
+    },
+    FirestoreConnection,
+};
+
+/// Differences between two snapshots of a collection.
+///
+/// Document paths are relative to the parent of the compared collection,
+/// e.g. `users/alice/orders/1`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CollectionDiff {
+    /// Documents present only in the new snapshot.
+    pub added: Vec<String>,
+    /// Documents present only in the old snapshot.
+    pub removed: Vec<String>,
+    /// Documents present in both snapshots with different fields.
+    pub changed: Vec<DocumentDiff>,
+    #[serde(skip)]
+    added_data: BTreeMap<String, HashMap<String, ValueType>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentDiff {
+    pub path: String,
+    pub fields: Vec<FieldChange>,
+}
+
+/// A change of a single field. Nested fields of maps are reported separately.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    /// Segments of the field path.
+    pub field: Vec<String>,
+    /// Value in the old snapshot, missing if the field was added.
+    pub old: Option<ValueType>,
+    /// Value in the new snapshot, missing if the field was removed.
+    pub new: Option<ValueType>,
+}
+
+impl CollectionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Expresses the diff as a JSON Patch (RFC 6902).
+    ///
+    /// The patch applies to the flat view of a snapshot returned by [`flatten_collection`].
+    pub fn to_json_patch(&self) -> serde_json::Value {
+        let mut operations = Vec::new();
+        for path in &self.removed {
+            operations.push(json!({ "op": "remove", "path": json_pointer(&[path]) }));
+        }
+        for path in &self.added {
+            operations.push(json!({
+                "op": "add",
+                "path": json_pointer(&[path]),
+                "value": self.added_data.get(path),
+            }));
+        }
+        for document in &self.changed {
+            for change in &document.fields {
+                let mut segments = vec![document.path.as_str()];
+                for (index, field) in change.field.iter().enumerate() {
+                    if index > 0 {
+                        segments.push("MapValue");
+                    }
+                    segments.push(field);
+                }
+                let path = json_pointer(&segments);
+                operations.push(match (&change.old, &change.new) {
+                    (None, Some(new)) => json!({ "op": "add", "path": path, "value": new }),
+                    (Some(_), None) => json!({ "op": "remove", "path": path }),
+                    (_, new) => json!({ "op": "replace", "path": path, "value": new }),
+                });
+            }
+        }
+        serde_json::Value::Array(operations)
+    }
+}
+
+impl Display for CollectionDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for path in &self.added {
+            writeln!(f, "+ {}", path)?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {}", path)?;
+        }
+        for document in &self.changed {
+            writeln!(f, "~ {}", document.path)?;
+            for change in &document.fields {
+                let field = change.field.join(".");
+                match (&change.old, &change.new) {
+                    (None, Some(new)) => writeln!(f, "    + {}: {:?}", field, new)?,
+                    (Some(old), None) => writeln!(f, "    - {}: {:?}", field, old)?,
+                    (old, new) => writeln!(f, "    ~ {}: {:?} -> {:?}", field, old, new)?,
+                }
+            }
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+/// Compares two snapshots of a collection.
+pub fn diff_collections(old: &CollectionData, new: &CollectionData) -> CollectionDiff {
+    let old_documents = flatten_documents(old);
+    let new_documents = flatten_documents(new);
+
+    let mut diff = CollectionDiff::default();
+    for (path, old_data) in &old_documents {
+        match new_documents.get(path) {
+            None => diff.removed.push(path.clone()),
+            Some(new_data) => {
+                let mut fields = Vec::new();
+                diff_fields(old_data, new_data, &[], &mut fields);
+                if !fields.is_empty() {
+                    diff.changed.push(DocumentDiff {
+                        path: path.clone(),
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+    for (path, new_data) in new_documents {
+        if !old_documents.contains_key(&path) {
+            diff.added.push(path.clone());
+            diff.added_data.insert(path, new_data.clone());
+        }
+    }
+    diff
+}
+
+/// Compares the collection currently stored in Firestore with the snapshot.
+///
+/// The live collection is the old side of the diff, so the diff describes
+/// what seeding the snapshot in replace mode would change.
+pub async fn diff_with_firestore(
+    conn: FirestoreConnection,
+    snapshot: &CollectionData,
+    parent_document_path: &str,
+) -> Result<CollectionDiff, SeedError> {
+    if !validate_document_path(parent_document_path) {
+        return Err(SeedError::InvalidPath);
+    }
+    let parent_path = parent_document_full_path(&conn.1, parent_document_path);
+    let live = collect_collection(conn, format!("{}/{}", parent_path, snapshot.id))
+        .await
+        .map_err(SeedError::FirestoreClientError)?;
+    Ok(diff_collections(&live, snapshot))
+}
+
+/// Flat view of a snapshot: an object mapping relative document paths to the document data.
+pub fn flatten_collection(collection: &CollectionData) -> serde_json::Value {
+    serde_json::to_value(flatten_documents(collection)).unwrap_or_default()
+}
+
+fn flatten_documents(collection: &CollectionData) -> BTreeMap<String, &HashMap<String, ValueType>> {
+    let mut documents = BTreeMap::new();
+    collect_documents(collection, "", &mut documents);
+    documents
+}
+
+fn collect_documents<'a>(
+    collection: &'a CollectionData,
+    prefix: &str,
+    documents: &mut BTreeMap<String, &'a HashMap<String, ValueType>>,
+) {
+    for document in &collection.documents {
+        let path = format!("{}{}/{}", prefix, collection.id, document.id);
+        documents.insert(path.clone(), &document.data);
+        for subcollection in document.subcollections.iter().flatten() {
+            collect_documents(subcollection, &format!("{}/", path), documents);
+        }
+    }
+}
+
+fn diff_fields(
+    old: &HashMap<String, ValueType>,
+    new: &HashMap<String, ValueType>,
+    prefix: &[String],
+    changes: &mut Vec<FieldChange>,
+) {
+    let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    for key in keys {
+        let mut field = prefix.to_vec();
+        field.push(key.clone());
+        match (old.get(key), new.get(key)) {
+            (Some(ValueType::MapValue(old_map)), Some(ValueType::MapValue(new_map))) => {
+                let old_map = unbox_map(old_map);
+                let new_map = unbox_map(new_map);
+                diff_fields(&old_map, &new_map, &field, changes)
+            }
+            (old_value, new_value) if old_value != new_value => changes.push(FieldChange {
+                field,
+                old: old_value.cloned(),
+                new: new_value.cloned(),
+            }),
+            _ => {}
+        }
+    }
+}
+
+fn unbox_map(map: &HashMap<String, Box<ValueType>>) -> HashMap<String, ValueType> {
+    map.iter()
+        .map(|(key, value)| (key.clone(), value.as_ref().clone()))
+        .collect()
+}
+
+/// Builds a JSON Pointer (RFC 6901) from unescaped segments.
+fn json_pointer(segments: &[&str]) -> String {
+    segments
+        .iter()
+        .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::seed::DocumentData;
+
+    fn document(
+        id: &str,
+        data: Vec<(&str, ValueType)>,
+        subcollections: Option<Vec<CollectionData>>,
+    ) -> DocumentData {
+        DocumentData {
+            id: id.to_string(),
+            data: data
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            subcollections,
+        }
+    }
+
+    fn snapshots() -> (CollectionData, CollectionData) {
+        let old = CollectionData {
+            id: "users".into(),
+            documents: vec![
+                document(
+                    "a",
+                    vec![
+                        ("name", ValueType::StringValue("Ann".into())),
+                        ("age", ValueType::IntegerValue(30)),
+                    ],
+                    None,
+                ),
+                document("b", vec![], None),
+            ],
+        };
+        let new = CollectionData {
+            id: "users".into(),
+            documents: vec![document(
+                "a",
+                vec![
+                    ("name", ValueType::StringValue("Anna".into())),
+                    ("city", ValueType::StringValue("Oslo".into())),
+                ],
+                Some(vec![CollectionData {
+                    id: "orders".into(),
+                    documents: vec![document("1", vec![], None)],
+                }]),
+            )],
+        };
+        (old, new)
+    }
+
+    #[test]
+    fn test_diff_collections() {
+        let (old, new) = snapshots();
+
+        let diff = diff_collections(&old, &new);
+
+        assert_eq!(diff.added, vec!["users/a/orders/1"]);
+        assert_eq!(diff.removed, vec!["users/b"]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(
+            diff.changed[0].fields,
+            vec![
+                FieldChange {
+                    field: vec!["age".into()],
+                    old: Some(ValueType::IntegerValue(30)),
+                    new: None,
+                },
+                FieldChange {
+                    field: vec!["city".into()],
+                    old: None,
+                    new: Some(ValueType::StringValue("Oslo".into())),
+                },
+                FieldChange {
+                    field: vec!["name".into()],
+                    old: Some(ValueType::StringValue("Ann".into())),
+                    new: Some(ValueType::StringValue("Anna".into())),
+                },
+            ]
+        );
+        assert!(diff_collections(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let (old, new) = snapshots();
+
+        let patch = diff_collections(&old, &new).to_json_patch();
+
+        assert_eq!(
+            patch,
+            json!([
+                { "op": "remove", "path": "/users~1b" },
+                { "op": "add", "path": "/users~1a~1orders~11", "value": {} },
+                { "op": "remove", "path": "/users~1a/age" },
+                { "op": "add", "path": "/users~1a/city", "value": { "StringValue": "Oslo" } },
+                { "op": "replace", "path": "/users~1a/name", "value": { "StringValue": "Anna" } },
+            ])
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use super::{
+    collect::{list_collection_ids, list_document_ids},
+    seed::{
+        commit_writes, generate_seed_writes, parent_document_full_path, validate_document_path,
+        write_document_name, CollectionData, SeedError, SeedOptions, SeedReport,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// Parent documents a snapshot is seeded under.
+#[derive(Debug, Clone)]
+pub enum ParentDocuments {
+    /// Paths of the parent documents relative to the database.
+    Paths(Vec<String>),
+    /// Path relative to the database where `*` in a segment matches any ID, e.g. `tenants/*`.
+    /// It is resolved by listing the matching collections and documents.
+    Pattern(String),
+}
+
+/// Result of seeding a snapshot under many parent documents.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FanOutReport {
+    /// Results of the parents, in the order of the parents.
+    pub parents: Vec<ParentSeedReport>,
+    /// Number of Firestore calls retried after a transient error.
+    pub retries: usize,
+    /// Number of batches skipped because the journal records them as committed.
+    pub resumed_batches: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ParentSeedReport {
+    pub parent: String,
+    /// Documents written and skipped under the parent.
+    /// Documents of batches resumed from the journal are counted as written.
+    pub report: SeedReport,
+}
+
+/// Seeds a copy of the snapshot under each of the parent documents.
+///
+/// Writes of all the parents share the batches and the concurrency of a single seed.
+pub async fn seed_collection_fan_out(
+    conn: FirestoreConnection,
+    collection: &CollectionData,
+    parents: &ParentDocuments,
+    options: &SeedOptions,
+) -> Result<FanOutReport, SeedError> {
+    let conn = conn.scoped_retries();
+    let parents = match parents {
+        ParentDocuments::Paths(paths) => paths.clone(),
+        ParentDocuments::Pattern(pattern) => resolve_parent_pattern(conn.clone(), pattern)
+            .await
+            .map_err(SeedError::FirestoreClientError)?,
+    };
+    if !parents.iter().all(|parent| validate_document_path(parent)) {
+        return Err(SeedError::InvalidPath);
+    }
+
+    let mut writes = Vec::new();
+    let mut write_parents = HashMap::new();
+    let mut reports = Vec::new();
+    let mut commit_options = options.clone();
+    for (index, parent) in parents.iter().enumerate() {
+        let parent_path = parent_document_full_path(&conn.1, parent);
+        let (parent_writes, id_mapping, parent_options) =
+            generate_seed_writes(collection, &parent_path, options);
+        commit_options = parent_options;
+        for write in &parent_writes {
+            write_parents.insert(write_document_name(write).to_string(), index);
+        }
+        reports.push(ParentSeedReport {
+            parent: parent.clone(),
+            report: SeedReport {
+                written: parent_writes.len(),
+                id_mapping,
+                ..SeedReport::default()
+            },
+        });
+        writes.extend(parent_writes);
+    }
+
+    let database_path = conn.1.trim_end_matches("/documents").to_string();
+    let seed_report = commit_writes(conn, &writes, &database_path, &commit_options)
+        .await
+        .map_err(SeedError::FirestoreClientError)?;
+    for skipped in seed_report.skipped {
+        if let Some(index) = write_parents.get(&skipped.path) {
+            let report = &mut reports[*index].report;
+            report.written -= 1;
+            report.skipped.push(skipped);
+        }
+    }
+    Ok(FanOutReport {
+        parents: reports,
+        retries: seed_report.retries,
+        resumed_batches: seed_report.resumed_batches,
+    })
+}
+
+/// Lists paths of the documents matching the pattern, relative to the database.
+pub async fn resolve_parent_pattern(
+    conn: FirestoreConnection,
+    pattern: &str,
+) -> Result<Vec<String>, BoxError> {
+    let segments = pattern
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    if segments.len() % 2 == 1 {
+        return Err(format!("The pattern {} does not point to documents", pattern).into());
+    }
+
+    let mut paths = vec![String::new()];
+    for (index, segment) in segments.iter().enumerate() {
+        let mut matching = Vec::new();
+        for path in &paths {
+            if !segment.contains('*') {
+                matching.push(format!("{}/{}", path, segment));
+                continue;
+            }
+            let full_path = parent_document_full_path(&conn.1, path);
+            let ids = if index % 2 == 0 {
+                list_collection_ids(conn.clone(), &full_path).await?
+            } else {
+                let (parent_path, collection_id) = full_path.rsplit_once('/').unwrap_or_default();
+                list_document_ids(conn.clone(), parent_path, collection_id).await?
+            };
+            matching.extend(
+                ids.into_iter()
+                    .filter(|id| matches_glob(segment, id))
+                    .map(|id| format!("{}/{}", path, id)),
+            );
+        }
+        paths = matching;
+    }
+    Ok(paths)
+}
+
+/// Matches the ID against a glob segment where `*` stands for any sequence of characters.
+pub(crate) fn matches_glob(glob: &str, id: &str) -> bool {
+    match glob.split_once('*') {
+        None => glob == id,
+        Some((prefix, rest)) => {
+            id.starts_with(prefix) && {
+                let id = &id[prefix.len()..];
+                (0..=id.len())
+                    .filter(|start| id.is_char_boundary(*start))
+                    .any(|start| matches_glob(rest, &id[start..]))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_glob() {
+        assert!(matches_glob("*", "tenant-1"));
+        assert!(matches_glob("tenant-*", "tenant-1"));
+        assert!(matches_glob("*-eu", "tenant-eu"));
+        assert!(matches_glob("t*-*", "tenant-1"));
+        assert!(matches_glob("tenant", "tenant"));
+        assert!(!matches_glob("tenant-*", "user-1"));
+        assert!(!matches_glob("*-eu", "tenant-us"));
+        assert!(!matches_glob("tenant", "tenants"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{HashMap, HashSet};
+
+use firestore_grpc::v1::{
+    document_transform::field_transform::TransformType, value::ValueType as FirestoreValueType,
+    write::Operation, Value, Write,
+};
+use rand::{distributions::Alphanumeric, Rng};
+
+/// Length of the IDs generated by Firestore clients.
+const AUTO_ID_LENGTH: usize = 20;
+
+/// Generates a random ID in the format of the IDs generated by Firestore clients.
+pub fn generate_auto_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(AUTO_ID_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Gives every written document a fresh auto-ID.
+///
+/// Subcollections follow their new parents and references pointing to documents of the writes
+/// are rewritten. Writes have to be ordered parents first, as generated by the seeder.
+/// Returns the mapping from the old to the new full document names.
+pub(crate) fn regenerate_ids(writes: &mut [Write]) -> HashMap<String, String> {
+    regenerate_ids_with(writes, generate_auto_id)
+}
+
+fn regenerate_ids_with<F>(writes: &mut [Write], mut generate_id: F) -> HashMap<String, String>
+where
+    F: FnMut() -> String,
+{
+    let mut mapping = HashMap::new();
+    let mut used_ids = HashSet::new();
+    for write in writes.iter() {
+        let name = match &write.operation {
+            Some(Operation::Update(document)) => &document.name,
+            _ => continue,
+        };
+        let (collection_path, _) = name.rsplit_once('/').unwrap_or_default();
+        let (parent_path, collection_id) = collection_path.rsplit_once('/').unwrap_or_default();
+        let parent_path = mapping
+            .get(parent_path)
+            .cloned()
+            .unwrap_or_else(|| parent_path.to_string());
+        let new_id = loop {
+            let id = generate_id();
+            if used_ids.insert(id.clone()) {
+                break id;
+            }
+        };
+        mapping.insert(
+            name.clone(),
+            format!("{}/{}/{}", parent_path, collection_id, new_id),
+        );
+    }
+
+    for write in writes.iter_mut() {
+        if let Some(Operation::Update(document)) = &mut write.operation {
+            if let Some(new_name) = mapping.get(&document.name) {
+                document.name = new_name.clone();
+            }
+            document
+                .fields
+                .values_mut()
+                .for_each(|value| rewrite_references(value, &mapping));
+        }
+        for transform in &mut write.update_transforms {
+            match &mut transform.transform_type {
+                Some(TransformType::AppendMissingElements(array))
+                | Some(TransformType::RemoveAllFromArray(array)) => array
+                    .values
+                    .iter_mut()
+                    .for_each(|value| rewrite_references(value, &mapping)),
+                _ => {}
+            }
+        }
+    }
+    mapping
+}
+
+fn rewrite_references(value: &mut Value, mapping: &HashMap<String, String>) {
+    match &mut value.value_type {
+        Some(FirestoreValueType::ReferenceValue(reference)) => {
+            if let Some(new_reference) = mapping.get(reference) {
+                *reference = new_reference.clone();
+            }
+        }
+        Some(FirestoreValueType::ArrayValue(array)) => array
+            .values
+            .iter_mut()
+            .for_each(|value| rewrite_references(value, mapping)),
+        Some(FirestoreValueType::MapValue(map)) => map
+            .fields
+            .values_mut()
+            .for_each(|value| rewrite_references(value, mapping)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::seed::{
+        generate_writes_for_collection, write_document_name, CollectionData, DocumentData,
+        ValueType,
+    };
+
+    #[test]
+    fn test_generate_auto_id() {
+        let id = generate_auto_id();
+        assert_eq!(id.len(), 20);
+        assert!(id.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn test_regenerate_ids() {
+        let base = "projects/p/databases/d/documents";
+        let mut data = HashMap::new();
+        data.insert(
+            "friend".to_string(),
+            ValueType::ReferenceValue(format!("{}/users/b", base)),
+        );
+        data.insert(
+            "boss".to_string(),
+            ValueType::ReferenceValue(format!("{}/users/x", base)),
+        );
+        let collection = CollectionData {
+            id: "users".into(),
+            documents: vec![
+                DocumentData {
+                    id: "a".into(),
+                    data,
+                    subcollections: Some(vec![CollectionData {
+                        id: "posts".into(),
+                        documents: vec![DocumentData {
+                            id: "1".into(),
+                            data: HashMap::new(),
+                            subcollections: None,
+                        }],
+                    }]),
+                },
+                DocumentData {
+                    id: "b".into(),
+                    data: HashMap::new(),
+                    subcollections: None,
+                },
+            ],
+        };
+        let mut writes = generate_writes_for_collection(&collection, base);
+        // a duplicate is generated once, it has to be replaced
+        let mut ids = vec!["N3", "N2", "N1", "N1"];
+
+        let mapping = regenerate_ids_with(&mut writes, || ids.pop().unwrap().to_string());
+
+        let names = writes.iter().map(write_document_name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                format!("{}/users/N1", base),
+                format!("{}/users/N1/posts/N2", base),
+                format!("{}/users/N3", base),
+            ]
+        );
+        assert_eq!(mapping[&format!("{}/users/a/posts/1", base)], names[1]);
+        match &writes[0].operation {
+            Some(Operation::Update(document)) => {
+                assert_eq!(
+                    document.fields["friend"].value_type,
+                    Some(FirestoreValueType::ReferenceValue(format!(
+                        "{}/users/N3",
+                        base
+                    )))
+                );
+                // references outside of the snapshot are left intact
+                assert_eq!(
+                    document.fields["boss"].value_type,
+                    Some(FirestoreValueType::ReferenceValue(format!(
+                        "{}/users/x",
+                        base
+                    )))
+                );
+            }
+            other => panic!("Unexpected operation {:?}", other),
+        }
+    }
+}
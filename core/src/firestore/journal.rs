@@ -0,0 +1,324 @@
+use std::collections::{BTreeSet, HashMap};
+
+use firestore_grpc::v1::{
+    document_transform::{field_transform::TransformType, FieldTransform},
+    precondition::ConditionType,
+    value::ValueType,
+    write::Operation,
+    Value, Write,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::{
+    hash::Fnv1a,
+    seed::{apply_options, SeedOptions},
+    BoxError,
+};
+
+/// Local record of the batches committed by a seed, used to resume an interrupted seed.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SeedJournal {
+    /// Fingerprint of the batches of the seed, see [`plan_fingerprint`].
+    plan: String,
+    /// Indexes of the batches which have been committed.
+    committed: BTreeSet<usize>,
+    #[serde(skip)]
+    path: String,
+}
+
+impl SeedJournal {
+    /// Opens the journal of the seed planned as `batches` committed with the options.
+    ///
+    /// With [`SeedOptions::resume`] set an existing journal is loaded, otherwise a fresh one
+    /// is started. Resuming fails when the journal was written for a different plan.
+    pub(crate) async fn open(
+        path: &str,
+        batches: &[Vec<Write>],
+        options: &SeedOptions,
+    ) -> Result<Self, BoxError> {
+        let plan = plan_fingerprint(batches, options);
+        if options.resume {
+            if let Ok(content) = fs::read_to_string(path).await {
+                let mut journal: SeedJournal = serde_json::from_str(&content)?;
+                if journal.plan != plan {
+                    return Err(format!(
+                        "The journal {} was written for a different seed and cannot be resumed",
+                        path
+                    )
+                    .into());
+                }
+                journal.path = path.to_string();
+                return Ok(journal);
+            }
+        }
+        let journal = SeedJournal {
+            plan,
+            committed: BTreeSet::new(),
+            path: path.to_string(),
+        };
+        journal.save().await?;
+        Ok(journal)
+    }
+
+    pub(crate) fn is_committed(&self, batch_index: usize) -> bool {
+        self.committed.contains(&batch_index)
+    }
+
+    /// Marks the batch as committed and persists the journal.
+    pub(crate) async fn record(&mut self, batch_index: usize) -> Result<(), BoxError> {
+        self.committed.insert(batch_index);
+        self.save().await
+    }
+
+    /// Replaces the journal file with a temporary file renamed over it,
+    /// so that an interrupted save leaves the previous journal intact.
+    async fn save(&self) -> Result<(), BoxError> {
+        let temporary_path = format!("{}.tmp", self.path);
+        fs::write(&temporary_path, serde_json::to_string(self)?).await?;
+        fs::rename(&temporary_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+/// Identifies the plan of a seed by the writes of every batch as they are committed with
+/// the options, including their fields, masks, transforms and preconditions.
+///
+/// Fields are hashed in the order of their keys, so the fingerprint does not depend
+/// on the order of the maps.
+fn plan_fingerprint(batches: &[Vec<Write>], options: &SeedOptions) -> String {
+    let mut hasher = Fnv1a::default();
+    for batch in batches {
+        hash_len(&mut hasher, batch.len());
+        for write in batch {
+            hash_write(&mut hasher, &apply_options(write.clone(), options));
+        }
+    }
+    format!("{}:{:016x}", batches.len(), hasher.finish())
+}
+
+// every part of variable length is prefixed with its length,
+// so that different writes never feed the same bytes to the hasher
+
+fn hash_len(hasher: &mut Fnv1a, len: usize) {
+    hasher.write(&(len as u64).to_be_bytes());
+}
+
+fn hash_bytes(hasher: &mut Fnv1a, bytes: &[u8]) {
+    hash_len(hasher, bytes.len());
+    hasher.write(bytes);
+}
+
+fn hash_write(hasher: &mut Fnv1a, write: &Write) {
+    match &write.operation {
+        Some(Operation::Update(document)) => {
+            hasher.write(b"u");
+            hash_bytes(hasher, document.name.as_bytes());
+            hash_fields(hasher, &document.fields);
+        }
+        Some(Operation::Delete(name)) => {
+            hasher.write(b"d");
+            hash_bytes(hasher, name.as_bytes());
+        }
+        Some(Operation::Transform(transform)) => {
+            hasher.write(b"t");
+            hash_bytes(hasher, transform.document.as_bytes());
+            hash_transforms(hasher, &transform.field_transforms);
+        }
+        None => hasher.write(b"-"),
+    }
+    match &write.update_mask {
+        Some(mask) => {
+            hasher.write(b"m");
+            hash_len(hasher, mask.field_paths.len());
+            for field_path in &mask.field_paths {
+                hash_bytes(hasher, field_path.as_bytes());
+            }
+        }
+        None => hasher.write(b"-"),
+    }
+    hash_transforms(hasher, &write.update_transforms);
+    let condition = write
+        .current_document
+        .as_ref()
+        .and_then(|precondition| precondition.condition_type.as_ref());
+    match condition {
+        Some(ConditionType::Exists(exists)) => hasher.write(&[b'e', *exists as u8]),
+        Some(ConditionType::UpdateTime(time)) => {
+            hasher.write(b"t");
+            hasher.write(&time.seconds.to_be_bytes());
+            hasher.write(&time.nanos.to_be_bytes());
+        }
+        None => hasher.write(b"-"),
+    }
+}
+
+fn hash_transforms(hasher: &mut Fnv1a, transforms: &[FieldTransform]) {
+    hash_len(hasher, transforms.len());
+    for transform in transforms {
+        hash_bytes(hasher, transform.field_path.as_bytes());
+        match &transform.transform_type {
+            Some(TransformType::SetToServerValue(value)) => {
+                hasher.write(b"s");
+                hasher.write(&value.to_be_bytes());
+            }
+            Some(TransformType::Increment(value)) => {
+                hasher.write(b"i");
+                hash_value(hasher, value);
+            }
+            Some(TransformType::Maximum(value)) => {
+                hasher.write(b"x");
+                hash_value(hasher, value);
+            }
+            Some(TransformType::Minimum(value)) => {
+                hasher.write(b"n");
+                hash_value(hasher, value);
+            }
+            Some(TransformType::AppendMissingElements(array)) => {
+                hasher.write(b"a");
+                hash_values(hasher, &array.values);
+            }
+            Some(TransformType::RemoveAllFromArray(array)) => {
+                hasher.write(b"r");
+                hash_values(hasher, &array.values);
+            }
+            None => hasher.write(b"-"),
+        }
+    }
+}
+
+fn hash_fields(hasher: &mut Fnv1a, fields: &HashMap<String, Value>) {
+    let mut keys = fields.keys().collect::<Vec<_>>();
+    keys.sort();
+    hash_len(hasher, keys.len());
+    for key in keys {
+        hash_bytes(hasher, key.as_bytes());
+        hash_value(hasher, &fields[key]);
+    }
+}
+
+fn hash_values(hasher: &mut Fnv1a, values: &[Value]) {
+    hash_len(hasher, values.len());
+    for value in values {
+        hash_value(hasher, value);
+    }
+}
+
+fn hash_value(hasher: &mut Fnv1a, value: &Value) {
+    match &value.value_type {
+        Some(ValueType::NullValue(_)) => hasher.write(b"0"),
+        Some(ValueType::BooleanValue(value)) => hasher.write(&[b'b', *value as u8]),
+        Some(ValueType::IntegerValue(value)) => {
+            hasher.write(b"i");
+            hasher.write(&value.to_be_bytes());
+        }
+        Some(ValueType::DoubleValue(value)) => {
+            hasher.write(b"d");
+            hasher.write(&value.to_bits().to_be_bytes());
+        }
+        Some(ValueType::TimestampValue(value)) => {
+            hasher.write(b"t");
+            hasher.write(&value.seconds.to_be_bytes());
+            hasher.write(&value.nanos.to_be_bytes());
+        }
+        Some(ValueType::StringValue(value)) => {
+            hasher.write(b"s");
+            hash_bytes(hasher, value.as_bytes());
+        }
+        Some(ValueType::BytesValue(value)) => {
+            hasher.write(b"y");
+            hash_bytes(hasher, value);
+        }
+        Some(ValueType::ReferenceValue(value)) => {
+            hasher.write(b"r");
+            hash_bytes(hasher, value.as_bytes());
+        }
+        Some(ValueType::GeoPointValue(point)) => {
+            hasher.write(b"g");
+            hasher.write(&point.latitude.to_bits().to_be_bytes());
+            hasher.write(&point.longitude.to_bits().to_be_bytes());
+        }
+        Some(ValueType::ArrayValue(array)) => {
+            hasher.write(b"a");
+            hash_values(hasher, &array.values);
+        }
+        Some(ValueType::MapValue(map)) => {
+            hasher.write(b"m");
+            hash_fields(hasher, &map.fields);
+        }
+        None => hasher.write(b"-"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firestore_grpc::v1::Document;
+
+    use super::*;
+    use crate::firestore::seed::delete_write;
+
+    fn update_write(name: &str, fields: Vec<(&str, i64)>) -> Write {
+        Write {
+            update_mask: None,
+            update_transforms: vec![],
+            current_document: None,
+            operation: Some(Operation::Update(Document {
+                name: name.to_string(),
+                fields: fields
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let value = Value {
+                            value_type: Some(ValueType::IntegerValue(value)),
+                        };
+                        (key.to_string(), value)
+                    })
+                    .collect(),
+                create_time: None,
+                update_time: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn test_plan_fingerprint() {
+        let options = SeedOptions::default();
+        let batches = vec![
+            vec![delete_write("a".into()), delete_write("b".into())],
+            vec![delete_write("c".into())],
+        ];
+        let rebatched = vec![
+            vec![delete_write("a".into())],
+            vec![delete_write("b".into()), delete_write("c".into())],
+        ];
+        assert_eq!(
+            plan_fingerprint(&batches, &options),
+            plan_fingerprint(&batches.clone(), &options)
+        );
+        assert_ne!(
+            plan_fingerprint(&batches, &options),
+            plan_fingerprint(&rebatched, &options)
+        );
+    }
+
+    #[test]
+    fn test_plan_fingerprint_covers_writes_and_options() {
+        let options = SeedOptions::default();
+        let fingerprint = |fields, options: &SeedOptions| {
+            plan_fingerprint(&[vec![update_write("a", fields)]], options)
+        };
+        let original = fingerprint(vec![("x", 1), ("y", 2)], &options);
+
+        assert_eq!(original, fingerprint(vec![("y", 2), ("x", 1)], &options));
+        assert_ne!(original, fingerprint(vec![("x", 1), ("y", 3)], &options));
+        assert_ne!(
+            original,
+            fingerprint(
+                vec![("x", 1), ("y", 2)],
+                &SeedOptions {
+                    merge: true,
+                    ..SeedOptions::default()
+                }
+            )
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+
+use firestore_grpc::v1::{
+    precondition::ConditionType, value::ValueType as FirestoreValueType, write::Operation,
+    Document, DocumentMask, Precondition, Write,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    collect::list_documents_page,
+    seed::{
+        commit_writes, from_firestore_value, quote_field_path_segment, to_firestore_value,
+        SeedOptions, SeedPrecondition, SkipReason, Timestamp, ValueType,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// Declarative changes applied to every matching document of a collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Migration {
+    /// Path of the collection relative to the database.
+    pub collection: String,
+    /// Only documents having all of these values are migrated.
+    /// Keys are field paths with segments separated by dots.
+    #[serde(default)]
+    pub filter: HashMap<String, ValueType>,
+    /// Operations applied one after another.
+    pub operations: Vec<MigrationOperation>,
+}
+
+/// A single change of a document. Fields are given as paths with segments separated by dots.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum MigrationOperation {
+    /// Moves the value of the field to another field.
+    Rename { from: String, to: String },
+    /// Removes the field.
+    Delete { field: String },
+    /// Sets the field when it is missing.
+    SetDefault { field: String, value: ValueType },
+    /// Converts the value of the field to another type.
+    ConvertType { field: String, to: FieldType },
+    /// Moves the fields into a map field, keeping their names.
+    MoveIntoMap { fields: Vec<String>, map: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    String,
+    Integer,
+    Double,
+    Boolean,
+    Timestamp,
+}
+
+/// Result of a migration.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrationReport {
+    /// Set when nothing has been written and the report is only a preview.
+    pub dry_run: bool,
+    /// Number of documents of the collection.
+    pub scanned: usize,
+    /// Documents which are changed by the migration.
+    pub migrated: Vec<MigratedDocument>,
+    /// Documents which could not be migrated, they are left unchanged.
+    pub failed: Vec<FailedDocument>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigratedDocument {
+    pub path: String,
+    /// Field paths written or deleted, the update mask of the write.
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedDocument {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Progress of a migration, reported after every page of documents.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MigrationProgress {
+    pub scanned: usize,
+    pub migrated: usize,
+}
+
+/// Applies the migration to every matching document of the collection.
+///
+/// Documents are read and written page by page. Writes carry an update mask of the changed
+/// fields only, so fields untouched by the migration are preserved even if they change meanwhile.
+/// Documents deleted in the meantime are reported as failed.
+/// With `dry_run` set only the report of the changes is returned.
+pub async fn migrate_collection<F>(
+    conn: FirestoreConnection,
+    migration: &Migration,
+    dry_run: bool,
+    on_progress: F,
+) -> Result<MigrationReport, BoxError>
+where
+    F: Fn(MigrationProgress) + Send + Sync,
+{
+    let full_path = format!("{}/{}", conn.1, migration.collection.trim_matches('/'));
+    let database_path = conn.1.trim_end_matches("/documents").to_string();
+    let filter = migration
+        .filter
+        .iter()
+        .map(|(path, value)| (split_field_path(path), value))
+        .collect::<Vec<_>>();
+    let mut report = MigrationReport {
+        dry_run,
+        ..MigrationReport::default()
+    };
+
+    let mut page_token = String::new();
+    loop {
+        let (documents, next_page_token) =
+            list_documents_page(conn.clone(), &full_path, page_token, false).await?;
+        let mut writes = Vec::new();
+        for document in documents {
+            report.scanned += 1;
+            let data = document_value(document.fields);
+            if !filter
+                .iter()
+                .all(|(path, value)| get_field(&data, path) == Some(value))
+            {
+                continue;
+            }
+            match migrate_document(&data, &migration.operations) {
+                Ok(Some((fields, mask))) => {
+                    report.migrated.push(MigratedDocument {
+                        path: document.name.clone(),
+                        fields: mask.clone(),
+                    });
+                    writes.push(update_write(document.name, fields, mask));
+                }
+                Ok(None) => {}
+                Err(reason) => report.failed.push(FailedDocument {
+                    path: document.name,
+                    reason,
+                }),
+            }
+        }
+        if !dry_run && !writes.is_empty() {
+            let options = SeedOptions {
+                precondition: SeedPrecondition::MustExist,
+                ..SeedOptions::default()
+            };
+            let seed_report =
+                commit_writes(conn.clone(), &writes, &database_path, &options).await?;
+            // documents deleted since they were listed are not migrated
+            for skipped in seed_report.skipped {
+                report
+                    .migrated
+                    .retain(|document| document.path != skipped.path);
+                let reason = match skipped.reason {
+                    SkipReason::Rejected(message) => message,
+                    _ => "The document has been deleted".to_string(),
+                };
+                report.failed.push(FailedDocument {
+                    path: skipped.path,
+                    reason,
+                });
+            }
+        }
+        on_progress(MigrationProgress {
+            scanned: report.scanned,
+            migrated: report.migrated.len(),
+        });
+        if next_page_token.is_empty() {
+            break;
+        }
+        page_token = next_page_token;
+    }
+    Ok(report)
+}
+
+/// Applies the operations to the document.
+///
+/// Returns the changed fields (nested in maps) together with the update mask of their paths,
+/// or `None` when the document does not change.
+fn migrate_document(
+    data: &ValueType,
+    operations: &[MigrationOperation],
+) -> Result<Option<(ValueType, Vec<String>)>, String> {
+    let mut migrated = data.clone();
+    let mut touched = Vec::new();
+    for operation in operations {
+        apply_operation(&mut migrated, operation, &mut touched)?;
+    }
+
+    let mut changed = touched
+        .into_iter()
+        .filter(|path| get_field(data, path) != get_field(&migrated, path))
+        .collect::<Vec<_>>();
+    changed.sort();
+    changed.dedup();
+    // a changed map covers its fields
+    let changed = changed
+        .iter()
+        .filter(|path| {
+            !changed
+                .iter()
+                .any(|other| other.len() < path.len() && path.starts_with(other))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if changed.is_empty() {
+        return Ok(None);
+    }
+
+    let mut fields = ValueType::MapValue(HashMap::new());
+    for path in &changed {
+        if let Some(value) = get_field(&migrated, path) {
+            set_field(&mut fields, path, value.clone());
+        }
+    }
+    let mask = changed
+        .iter()
+        .map(|path| {
+            path.iter()
+                .map(|segment| quote_field_path_segment(segment))
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect();
+    Ok(Some((fields, mask)))
+}
+
+pub(crate) fn apply_operation(
+    data: &mut ValueType,
+    operation: &MigrationOperation,
+    touched: &mut Vec<Vec<String>>,
+) -> Result<(), String> {
+    match operation {
+        MigrationOperation::Rename { from, to } => {
+            let (from, to) = (split_field_path(from), split_field_path(to));
+            if let Some(value) = remove_field(data, &from) {
+                set_field(data, &to, value);
+                touched.extend(vec![from, to]);
+            }
+        }
+        MigrationOperation::Delete { field } => {
+            let field = split_field_path(field);
+            if remove_field(data, &field).is_some() {
+                touched.push(field);
+            }
+        }
+        MigrationOperation::SetDefault { field, value } => {
+            let field = split_field_path(field);
+            if get_field(data, &field).is_none() {
+                set_field(data, &field, value.clone());
+                touched.push(field);
+            }
+        }
+        MigrationOperation::ConvertType { field, to } => {
+            let field = split_field_path(field);
+            if let Some(value) = get_field(data, &field) {
+                let converted = convert_value(value, *to)?;
+                set_field(data, &field, converted);
+                touched.push(field);
+            }
+        }
+        MigrationOperation::MoveIntoMap { fields, map } => {
+            let map = split_field_path(map);
+            for field in fields {
+                let field = split_field_path(field);
+                if let Some(value) = remove_field(data, &field) {
+                    let mut target = map.clone();
+                    target.extend(field.last().cloned());
+                    set_field(data, &target, value);
+                    touched.extend(vec![field, target]);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn convert_value(value: &ValueType, to: FieldType) -> Result<ValueType, String> {
+    let converted = match (to, value) {
+        (FieldType::String, ValueType::StringValue(value)) => {
+            Some(ValueType::StringValue(value.clone()))
+        }
+        (FieldType::String, ValueType::IntegerValue(value)) => {
+            Some(ValueType::StringValue(value.to_string()))
+        }
+        (FieldType::String, ValueType::DoubleValue(value)) => {
+            Some(ValueType::StringValue(value.to_string()))
+        }
+        (FieldType::String, ValueType::BooleanValue(value)) => {
+            Some(ValueType::StringValue(value.to_string()))
+        }
+        (FieldType::Integer, ValueType::IntegerValue(value)) => {
+            Some(ValueType::IntegerValue(*value))
+        }
+        (FieldType::Integer, ValueType::DoubleValue(value)) => {
+            Some(ValueType::IntegerValue(value.trunc() as i64))
+        }
+        (FieldType::Integer, ValueType::BooleanValue(value)) => {
+            Some(ValueType::IntegerValue(*value as i64))
+        }
+        (FieldType::Integer, ValueType::StringValue(value)) => {
+            value.trim().parse().ok().map(ValueType::IntegerValue)
+        }
+        (FieldType::Integer, ValueType::TimestampValue(value)) => {
+            Some(ValueType::IntegerValue(value.seconds))
+        }
+        (FieldType::Double, ValueType::DoubleValue(value)) => Some(ValueType::DoubleValue(*value)),
+        (FieldType::Double, ValueType::IntegerValue(value)) => {
+            Some(ValueType::DoubleValue(*value as f64))
+        }
+        (FieldType::Double, ValueType::StringValue(value)) => {
+            value.trim().parse().ok().map(ValueType::DoubleValue)
+        }
+        (FieldType::Boolean, ValueType::BooleanValue(value)) => {
+            Some(ValueType::BooleanValue(*value))
+        }
+        (FieldType::Boolean, ValueType::IntegerValue(value)) => {
+            Some(ValueType::BooleanValue(*value != 0))
+        }
+        (FieldType::Boolean, ValueType::StringValue(value)) => {
+            value.trim().parse().ok().map(ValueType::BooleanValue)
+        }
+        (FieldType::Timestamp, ValueType::TimestampValue(value)) => {
+            Some(ValueType::TimestampValue(*value))
+        }
+        (FieldType::Timestamp, ValueType::IntegerValue(value)) => {
+            Some(ValueType::TimestampValue(Timestamp::from(*value)))
+        }
+        _ => None,
+    };
+    converted.ok_or_else(|| format!("Cannot convert {:?} to {:?}", value, to))
+}
+
+fn update_write(name: String, fields: ValueType, mask: Vec<String>) -> Write {
+    let fields = match to_firestore_value(fields) {
+        FirestoreValueType::MapValue(map) => map.fields,
+        _ => HashMap::new(),
+    };
+    Write {
+        update_mask: Some(DocumentMask { field_paths: mask }),
+        update_transforms: vec![],
+        // the document could have been deleted in the meantime
+        current_document: Some(Precondition {
+            condition_type: Some(ConditionType::Exists(true)),
+        }),
+        operation: Some(Operation::Update(Document {
+            name,
+            fields,
+            create_time: None,
+            update_time: None,
+        })),
+    }
+}
+
+/// Converts the fields of a document to a map value, so that documents and maps are handled alike.
+fn document_value(fields: HashMap<String, firestore_grpc::v1::Value>) -> ValueType {
+    ValueType::MapValue(
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| value.value_type.map(|value| (key, value)))
+            .map(|(key, value)| (key, Box::new(from_firestore_value(value))))
+            .collect(),
+    )
+}
+
+pub(crate) fn split_field_path(path: &str) -> Vec<String> {
+    path.split('.').map(String::from).collect()
+}
+
+pub(crate) fn get_field<'a>(value: &'a ValueType, path: &[String]) -> Option<&'a ValueType> {
+    match path.split_first() {
+        None => Some(value),
+        Some((first, rest)) => match value {
+            ValueType::MapValue(map) => get_field(map.get(first)?, rest),
+            _ => None,
+        },
+    }
+}
+
+pub(crate) fn remove_field(value: &mut ValueType, path: &[String]) -> Option<ValueType> {
+    let (first, rest) = path.split_first()?;
+    match value {
+        ValueType::MapValue(map) if rest.is_empty() => map.remove(first).map(|value| *value),
+        ValueType::MapValue(map) => remove_field(map.get_mut(first)?, rest),
+        _ => None,
+    }
+}
+
+/// Sets the field, creating the missing maps on the way.
+pub(crate) fn set_field(value: &mut ValueType, path: &[String], new_value: ValueType) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if let ValueType::MapValue(map) = value {
+        if rest.is_empty() {
+            map.insert(first.clone(), Box::new(new_value));
+            return;
+        }
+        let child = map
+            .entry(first.clone())
+            .or_insert_with(|| Box::new(ValueType::MapValue(HashMap::new())));
+        if !matches!(**child, ValueType::MapValue(_)) {
+            **child = ValueType::MapValue(HashMap::new());
+        }
+        set_field(child, rest, new_value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(fields: Vec<(&str, ValueType)>) -> ValueType {
+        ValueType::MapValue(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), Box::new(value)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_migrate_document() {
+        let data = document(vec![
+            ("name", ValueType::StringValue("Jane".into())),
+            ("age", ValueType::StringValue("42".into())),
+            ("city", ValueType::StringValue("Paris".into())),
+            ("legacy", ValueType::BooleanValue(true)),
+        ]);
+        let operations = vec![
+            MigrationOperation::Rename {
+                from: "name".into(),
+                to: "full name".into(),
+            },
+            MigrationOperation::Delete {
+                field: "legacy".into(),
+            },
+            MigrationOperation::SetDefault {
+                field: "active".into(),
+                value: ValueType::BooleanValue(true),
+            },
+            MigrationOperation::ConvertType {
+                field: "age".into(),
+                to: FieldType::Integer,
+            },
+            MigrationOperation::MoveIntoMap {
+                fields: vec!["city".into()],
+                map: "address".into(),
+            },
+        ];
+
+        let (fields, mask) = migrate_document(&data, &operations).unwrap().unwrap();
+
+        assert_eq!(
+            mask,
+            vec![
+                "active",
+                "address.city",
+                "age",
+                "city",
+                "`full name`",
+                "legacy",
+                "name"
+            ]
+        );
+        assert_eq!(
+            fields,
+            document(vec![
+                ("full name", ValueType::StringValue("Jane".into())),
+                ("active", ValueType::BooleanValue(true)),
+                ("age", ValueType::IntegerValue(42)),
+                (
+                    "address",
+                    document(vec![("city", ValueType::StringValue("Paris".into()))])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_migrate_document_without_changes() {
+        let data = document(vec![("active", ValueType::BooleanValue(false))]);
+        let operations = vec![MigrationOperation::SetDefault {
+            field: "active".into(),
+            value: ValueType::BooleanValue(true),
+        }];
+
+        assert_eq!(migrate_document(&data, &operations), Ok(None));
+    }
+
+    #[test]
+    fn test_migrate_document_with_invalid_conversion() {
+        let data = document(vec![("age", ValueType::StringValue("unknown".into()))]);
+        let operations = vec![MigrationOperation::ConvertType {
+            field: "age".into(),
+            to: FieldType::Integer,
+        }];
+
+        assert!(migrate_document(&data, &operations).is_err());
+    }
+}
@@ -5,15 +5,57 @@ use firestore_grpc::tonic::{
     transport::{Channel, ClientTlsConfig},
 };
 
+use retry::{Retrier, RetryPolicy};
+
+pub mod anonymize;
+pub mod bulk;
 pub mod collect;
+pub mod copy;
+pub mod delete;
+pub mod diff;
+pub mod fanout;
+mod hash;
+pub mod ids;
+mod journal;
+pub mod migrate;
+pub mod plan;
+pub mod references;
+pub mod relocate;
+pub mod replace;
+pub mod retry;
+pub mod rollback;
+pub mod sample;
 pub mod seed;
+pub mod sync;
+mod throttle;
+pub mod transform;
 mod type_mapping;
+pub mod versioning;
 
 #[derive(Clone)]
-pub struct FirestoreConnection(pub FirestoreClient, pub String);
+pub struct FirestoreConnection(pub FirestoreClient, pub String, pub Retrier);
+
+impl FirestoreConnection {
+    /// Connection to the documents of a database, e.g. `projects/{project}/databases/(default)/documents`,
+    /// retrying transient errors with the default [`RetryPolicy`].
+    pub fn new(client: FirestoreClient, documents_path: String) -> Self {
+        FirestoreConnection(client, documents_path, Retrier::default())
+    }
+
+    /// Replaces the retry policy of the connection.
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> Self {
+        FirestoreConnection(self.0, self.1, Retrier::new(policy))
+    }
+
+    /// Clone of the connection counting its retries separately from the original,
+    /// so that the retries of a single operation can be reported.
+    pub fn scoped_retries(&self) -> Self {
+        FirestoreConnection(self.0.clone(), self.1.clone(), self.2.scoped())
+    }
+}
 
-const URL: &'static str = "https://firestore.googleapis.com";
-const DOMAIN: &'static str = "firestore.googleapis.com";
+const URL: &str = "https://firestore.googleapis.com";
+const DOMAIN: &str = "firestore.googleapis.com";
 
 pub type BoxError = Box<dyn std::error::Error + Sync + Send + 'static>;
 pub type FirestoreClient = firestore_grpc::v1::firestore_client::FirestoreClient<InterceptedService<Channel, AuthInterceptor>>;
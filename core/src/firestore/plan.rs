@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use firestore_grpc::v1::{value::ValueType as FirestoreValueType, write::Operation, Value, Write};
+use serde::Serialize;
+
+use super::{
+    collect::get_documents,
+    seed::{
+        generate_seed_writes, ordered_batches, parent_document_full_path, validate_document_path,
+        write_document_name, CollectionData, SeedError, SeedOptions, SeedPrecondition,
+    },
+    FirestoreConnection,
+};
+
+/// What seeding a snapshot would do, batch by batch, without writing anything.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeedPlan {
+    pub batches: Vec<PlannedBatch>,
+}
+
+/// A single commit of the seeder.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedBatch {
+    pub index: usize,
+    pub operations: Vec<PlannedOperation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedOperation {
+    pub path: String,
+    pub kind: PlannedOperationKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PlannedOperationKind {
+    /// The document does not exist and would be created.
+    Create,
+    /// The document exists and its content would change.
+    Overwrite,
+    /// The document exists and already has the content from the snapshot.
+    Unchanged,
+    /// The document would not be written because of the precondition.
+    Skip,
+}
+
+impl SeedPlan {
+    /// Counts planned operations of the given kind.
+    pub fn count(&self, kind: PlannedOperationKind) -> usize {
+        self.batches
+            .iter()
+            .flat_map(|batch| &batch.operations)
+            .filter(|operation| operation.kind == kind)
+            .count()
+    }
+}
+
+/// Plans seeding the collection without writing anything.
+///
+/// Every document of the snapshot is compared with its live version to find out
+/// whether it would be created, overwritten or left unchanged.
+/// Batches follow the same boundaries the seeder uses.
+pub async fn plan_seed(
+    conn: FirestoreConnection,
+    collection: &CollectionData,
+    parent_document_path: &str,
+    options: &SeedOptions,
+) -> Result<SeedPlan, SeedError> {
+    if !validate_document_path(parent_document_path) {
+        return Err(SeedError::InvalidPath);
+    }
+    let parent_path = parent_document_full_path(&conn.1, parent_document_path);
+    let (writes, _, options) = generate_seed_writes(collection, &parent_path, options);
+    let options = &options;
+
+    let mut plan = SeedPlan::default();
+    for (index, batch) in ordered_batches(&writes).into_iter().flatten().enumerate() {
+        let names = batch
+            .iter()
+            .map(|write| write_document_name(write).to_string())
+            .collect();
+        let existing = get_documents(conn.clone(), names)
+            .await
+            .map_err(SeedError::FirestoreClientError)?;
+        let operations = batch
+            .iter()
+            .map(|write| {
+                let path = write_document_name(write).to_string();
+                let live_fields = existing.get(&path).map(|document| &document.fields);
+                PlannedOperation {
+                    kind: plan_operation(write, live_fields, options),
+                    path,
+                }
+            })
+            .collect();
+        plan.batches.push(PlannedBatch { index, operations });
+    }
+    Ok(plan)
+}
+
+fn plan_operation(
+    write: &Write,
+    live_fields: Option<&HashMap<String, Value>>,
+    options: &SeedOptions,
+) -> PlannedOperationKind {
+    let fields = match &write.operation {
+        Some(Operation::Update(document)) => &document.fields,
+        _ => return PlannedOperationKind::Overwrite,
+    };
+    match (options.precondition, live_fields) {
+        (SeedPrecondition::MustNotExist, Some(_)) | (SeedPrecondition::MustExist, None) => {
+            PlannedOperationKind::Skip
+        }
+        (_, None) => PlannedOperationKind::Create,
+        (_, Some(live_fields)) => {
+            let written = if options.merge {
+                merge_fields(live_fields, fields)
+            } else {
+                fields.clone()
+            };
+            // field transforms always compute new values on the server
+            if &written == live_fields && write.update_transforms.is_empty() {
+                PlannedOperationKind::Unchanged
+            } else {
+                PlannedOperationKind::Overwrite
+            }
+        }
+    }
+}
+
+/// Applies the fields on top of the live ones the way a merge with a leaf field mask does.
+fn merge_fields(
+    live_fields: &HashMap<String, Value>,
+    fields: &HashMap<String, Value>,
+) -> HashMap<String, Value> {
+    let mut merged = live_fields.clone();
+    for (key, value) in fields {
+        let merged_value = match (&value.value_type, merged.get(key).map(|v| &v.value_type)) {
+            (
+                Some(FirestoreValueType::MapValue(map)),
+                Some(Some(FirestoreValueType::MapValue(live_map))),
+            ) if !map.fields.is_empty() => {
+                let mut live_map = live_map.clone();
+                live_map.fields = merge_fields(&live_map.fields, &map.fields);
+                Value {
+                    value_type: Some(FirestoreValueType::MapValue(live_map)),
+                }
+            }
+            _ => value.clone(),
+        };
+        merged.insert(key.clone(), merged_value);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firestore_grpc::v1::{Document, MapValue};
+
+    fn value(value_type: FirestoreValueType) -> Value {
+        Value {
+            value_type: Some(value_type),
+        }
+    }
+
+    fn update(fields: HashMap<String, Value>) -> Write {
+        Write {
+            update_mask: None,
+            update_transforms: vec![],
+            current_document: None,
+            operation: Some(Operation::Update(Document {
+                name: "projects/p/databases/(default)/documents/users/a".into(),
+                fields,
+                create_time: None,
+                update_time: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn test_plan_operation() {
+        let mut live = HashMap::new();
+        live.insert(
+            "name".to_string(),
+            value(FirestoreValueType::StringValue("Jane".into())),
+        );
+        live.insert(
+            "age".to_string(),
+            value(FirestoreValueType::IntegerValue(30)),
+        );
+        let mut snapshot = HashMap::new();
+        snapshot.insert(
+            "name".to_string(),
+            value(FirestoreValueType::StringValue("Jane".into())),
+        );
+        let operation = update(snapshot);
+        let overwrite = SeedOptions::default();
+        let merge = SeedOptions {
+            merge: true,
+            ..SeedOptions::default()
+        };
+        let create_only = SeedOptions {
+            precondition: SeedPrecondition::MustNotExist,
+            ..SeedOptions::default()
+        };
+
+        assert_eq!(
+            plan_operation(&operation, None, &overwrite),
+            PlannedOperationKind::Create
+        );
+        assert_eq!(
+            plan_operation(&operation, Some(&live), &overwrite),
+            PlannedOperationKind::Overwrite
+        );
+        assert_eq!(
+            plan_operation(&operation, Some(&live), &merge),
+            PlannedOperationKind::Unchanged
+        );
+        assert_eq!(
+            plan_operation(&operation, Some(&live), &create_only),
+            PlannedOperationKind::Skip
+        );
+    }
+
+    #[test]
+    fn test_merge_fields_descends_into_maps() {
+        let map = |entries: Vec<(&str, i64)>| {
+            value(FirestoreValueType::MapValue(MapValue {
+                fields: entries
+                    .into_iter()
+                    .map(|(key, number)| {
+                        (
+                            key.to_string(),
+                            value(FirestoreValueType::IntegerValue(number)),
+                        )
+                    })
+                    .collect(),
+            }))
+        };
+        let mut live = HashMap::new();
+        live.insert("stats".to_string(), map(vec![("a", 1), ("b", 2)]));
+        let mut fields = HashMap::new();
+        fields.insert("stats".to_string(), map(vec![("b", 3)]));
+
+        let merged = merge_fields(&live, &fields);
+
+        assert_eq!(merged["stats"], map(vec![("a", 1), ("b", 3)]));
+    }
+}
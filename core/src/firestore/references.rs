@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, HashSet};
+
+use firestore_grpc::v1::Document;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    collect::get_documents,
+    seed::{
+        from_firestore_value, seed_collection_with_options, CollectionData, DocumentData,
+        SeedError, SeedOptions, SeedReport, ValueType,
+    },
+    transform::DocumentTransformer,
+    BoxError, FirestoreConnection,
+};
+
+/// Snapshot of a collection together with the referenced documents of other collections,
+/// which are placed under their own paths.
+///
+/// Serialized as the collection with an additional `references` key, which is left out when
+/// there are no referenced documents, so every collection file is a snapshot as well.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(flatten)]
+    pub collection: CollectionData,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub references: Vec<ReferencedCollection>,
+}
+
+/// Documents of other collections referenced from a snapshot.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReferencedDocuments {
+    /// Referenced documents without their subcollections, grouped by their collections.
+    pub collections: Vec<ReferencedCollection>,
+    /// Full names of referenced documents which do not exist.
+    pub missing: Vec<String>,
+}
+
+/// A collection with the referenced documents, which can be seeded under its parent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferencedCollection {
+    /// Path of the parent document relative to the database, `/` for root collections.
+    pub parent: String,
+    pub collection: CollectionData,
+}
+
+/// Fetches the documents referenced from the collection, and the documents referenced from them,
+/// until no reference of the database points outside of the collection and the fetched documents.
+///
+/// With `max_depth` set, references are followed only that many steps from the collection,
+/// `1` fetches the documents referenced directly from the collection.
+/// Referenced documents belonging to the collection itself are added to it, and so are documents
+/// of subcollections whose parent documents are part of the collection.
+/// References to other databases are not followed.
+pub async fn include_referenced_documents(
+    conn: FirestoreConnection,
+    collection: &mut CollectionData,
+    collection_full_path: &str,
+    max_depth: Option<usize>,
+) -> Result<ReferencedDocuments, BoxError> {
+    follow_references(conn, collection, collection_full_path, max_depth, None).await
+}
+
+/// Fetches the referenced documents like [`include_referenced_documents`], passing every fetched
+/// document through the transformer before its references are followed.
+///
+/// The collection is expected to be transformed already, e.g. by
+/// [`collect_collection_transformed`](super::transform::collect_collection_transformed).
+pub async fn include_referenced_documents_transformed(
+    conn: FirestoreConnection,
+    collection: &mut CollectionData,
+    collection_full_path: &str,
+    max_depth: Option<usize>,
+    transformer: &dyn DocumentTransformer,
+) -> Result<ReferencedDocuments, BoxError> {
+    follow_references(
+        conn,
+        collection,
+        collection_full_path,
+        max_depth,
+        Some(transformer),
+    )
+    .await
+}
+
+pub(crate) async fn follow_references(
+    conn: FirestoreConnection,
+    collection: &mut CollectionData,
+    collection_full_path: &str,
+    max_depth: Option<usize>,
+    transformer: Option<&dyn DocumentTransformer>,
+) -> Result<ReferencedDocuments, BoxError> {
+    let database_prefix = format!("{}/", conn.1);
+    let mut known = HashSet::new();
+    let mut pending = Vec::new();
+    scan_collection(collection, collection_full_path, &mut known, &mut pending);
+
+    let mut fetched = Vec::new();
+    let mut missing = Vec::new();
+    let mut depth = 0;
+    while max_depth.is_none_or(|max_depth| depth < max_depth) {
+        depth += 1;
+        let mut names = pending
+            .drain(..)
+            .filter(|name: &String| name.starts_with(&database_prefix) && !known.contains(name))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            break;
+        }
+        let mut found = get_documents(conn.clone(), names.clone()).await?;
+        for name in names {
+            known.insert(name.clone());
+            match found.remove(&name) {
+                Some(document) => {
+                    let document = document_data(document);
+                    let documents = match transformer {
+                        Some(transformer) => {
+                            transformer.transform(&name[database_prefix.len()..], document)?
+                        }
+                        None => vec![document],
+                    };
+                    let (collection_name, _) = name.rsplit_once('/').unwrap_or_default();
+                    for document in documents {
+                        document
+                            .data
+                            .values()
+                            .for_each(|value| scan_value(value, &mut pending));
+                        fetched.push((format!("{}/{}", collection_name, document.id), document));
+                    }
+                }
+                None => missing.push(name),
+            }
+        }
+    }
+
+    // parents are nested before their subcollections
+    fetched.sort_by_key(|(name, _)| name.matches('/').count());
+    let collection_prefix = format!("{}/", collection_full_path);
+    let mut collections = BTreeMap::new();
+    for (name, document) in fetched {
+        let document = match name.strip_prefix(&collection_prefix) {
+            Some(relative_name) => {
+                let segments = relative_name.split('/').collect::<Vec<_>>();
+                match nest_document(collection, &segments, document) {
+                    Some(document) => document,
+                    None => continue,
+                }
+            }
+            None => document,
+        };
+        let (collection_path, _) = name.rsplit_once('/').unwrap_or_default();
+        let relative_path = &collection_path[database_prefix.len()..];
+        let (parent, collection_id) = relative_path
+            .rsplit_once('/')
+            .unwrap_or(("", relative_path));
+        collections
+            .entry((format!("/{}", parent), collection_id.to_string()))
+            .or_insert_with(Vec::new)
+            .push(document);
+    }
+    Ok(ReferencedDocuments {
+        collections: collections
+            .into_iter()
+            .map(|((parent, id), documents)| ReferencedCollection {
+                parent,
+                collection: CollectionData { id, documents },
+            })
+            .collect(),
+        missing,
+    })
+}
+
+/// Adds the document to the collection under the path given by the segments relative to it,
+/// creating the subcollections on the way.
+///
+/// Returns the document back when one of its parent documents is not part of the collection.
+fn nest_document(
+    collection: &mut CollectionData,
+    segments: &[&str],
+    document: DocumentData,
+) -> Option<DocumentData> {
+    let (parent_id, collection_id, rest) = match segments {
+        [_] => {
+            collection.documents.push(document);
+            return None;
+        }
+        [parent_id, collection_id, rest @ ..] => (parent_id, collection_id, rest),
+        [] => return Some(document),
+    };
+    let parent = match collection
+        .documents
+        .iter_mut()
+        .find(|parent| parent.id == *parent_id)
+    {
+        Some(parent) => parent,
+        None => return Some(document),
+    };
+    let subcollections = parent.subcollections.get_or_insert_with(Vec::new);
+    let index = match subcollections
+        .iter()
+        .position(|subcollection| subcollection.id == *collection_id)
+    {
+        Some(index) => index,
+        None => {
+            subcollections.push(CollectionData {
+                id: collection_id.to_string(),
+                documents: vec![],
+            });
+            subcollections.len() - 1
+        }
+    };
+    nest_document(&mut subcollections[index], rest, document)
+}
+
+/// Seeds the referenced documents of a snapshot under their parents, see [`Snapshot::references`].
+///
+/// The documents keep their IDs so that the references of the snapshot point to them,
+/// [`SeedOptions::regenerate_ids`] only applies to the snapshot itself.
+/// The journal and the undo log of the options are not supported and ignored.
+pub async fn seed_referenced_documents(
+    conn: FirestoreConnection,
+    collections: &[ReferencedCollection],
+    options: &SeedOptions,
+) -> Result<SeedReport, SeedError> {
+    let options = SeedOptions {
+        journal: None,
+        resume: false,
+        undo_log: None,
+        regenerate_ids: false,
+        ..options.clone()
+    };
+    let mut report = SeedReport::default();
+    for referenced in collections {
+        let collection_report = seed_collection_with_options(
+            conn.clone(),
+            &referenced.collection,
+            &referenced.parent,
+            &options,
+        )
+        .await?;
+        report.written += collection_report.written;
+        report.skipped.extend(collection_report.skipped);
+        report.retries += collection_report.retries;
+    }
+    Ok(report)
+}
+
+/// Records full names of the documents of the collection and the references they contain.
+fn scan_collection(
+    collection: &CollectionData,
+    collection_full_path: &str,
+    names: &mut HashSet<String>,
+    references: &mut Vec<String>,
+) {
+    for document in &collection.documents {
+        let name = format!("{}/{}", collection_full_path, document.id);
+        document
+            .data
+            .values()
+            .for_each(|value| scan_value(value, references));
+        for subcollection in document.subcollections.iter().flatten() {
+            let subcollection_path = format!("{}/{}", name, subcollection.id);
+            scan_collection(subcollection, &subcollection_path, names, references);
+        }
+        names.insert(name);
+    }
+}
+
+fn scan_value(value: &ValueType, references: &mut Vec<String>) {
+    match value {
+        ValueType::ReferenceValue(name) => references.push(name.clone()),
+        ValueType::ArrayValue(values)
+        | ValueType::ArrayUnion(values)
+        | ValueType::ArrayRemove(values) => values
+            .iter()
+            .for_each(|value| scan_value(value, references)),
+        ValueType::MapValue(map) => map.values().for_each(|value| scan_value(value, references)),
+        _ => {}
+    }
+}
+
+fn document_data(document: Document) -> DocumentData {
+    DocumentData {
+        id: document
+            .name
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        data: document
+            .fields
+            .into_iter()
+            .filter_map(|(key, value)| {
+                value
+                    .value_type
+                    .map(|value| (key, from_firestore_value(value)))
+            })
+            .collect(),
+        subcollections: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_scan_collection() {
+        let base = "projects/p/databases/(default)/documents";
+        let order = DocumentData {
+            id: "o1".into(),
+            data: vec![(
+                "items".to_string(),
+                ValueType::ArrayValue(vec![Box::new(ValueType::ReferenceValue(format!(
+                    "{}/products/p1",
+                    base
+                )))]),
+            )]
+            .into_iter()
+            .collect(),
+            subcollections: None,
+        };
+        let user = DocumentData {
+            id: "u1".into(),
+            data: vec![(
+                "company".to_string(),
+                ValueType::ReferenceValue(format!("{}/companies/c1", base)),
+            )]
+            .into_iter()
+            .collect(),
+            subcollections: Some(vec![CollectionData {
+                id: "orders".into(),
+                documents: vec![order],
+            }]),
+        };
+        let collection = CollectionData {
+            id: "users".into(),
+            documents: vec![user],
+        };
+        let (mut names, mut references) = (HashSet::new(), Vec::new());
+
+        scan_collection(
+            &collection,
+            &format!("{}/users", base),
+            &mut names,
+            &mut references,
+        );
+
+        references.sort();
+        assert_eq!(
+            references,
+            vec![
+                format!("{}/companies/c1", base),
+                format!("{}/products/p1", base)
+            ]
+        );
+        assert!(names.contains(&format!("{}/users/u1/orders/o1", base)));
+        assert_eq!(names.len(), 2);
+    }
+
+    #[test]
+    fn test_nest_document() {
+        let document = |id: &str| DocumentData {
+            id: id.into(),
+            data: HashMap::new(),
+            subcollections: None,
+        };
+        let mut collection = CollectionData {
+            id: "users".into(),
+            documents: vec![document("u1")],
+        };
+
+        assert!(nest_document(&mut collection, &["u2"], document("u2")).is_none());
+        assert!(nest_document(&mut collection, &["u1", "orders", "o1"], document("o1")).is_none());
+        assert!(nest_document(&mut collection, &["u1", "orders", "o2"], document("o2")).is_none());
+        assert_eq!(
+            nest_document(&mut collection, &["u3", "orders", "o3"], document("o3")),
+            Some(document("o3"))
+        );
+
+        let ids = collection
+            .documents
+            .iter()
+            .map(|document| document.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["u1", "u2"]);
+        let orders = collection.documents[0].subcollections.as_ref().unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].documents, vec![document("o1"), document("o2")]);
+    }
+
+    #[test]
+    fn test_snapshot_serialization() {
+        let collection = CollectionData {
+            id: "orders".into(),
+            documents: vec![],
+        };
+        let snapshot = Snapshot {
+            collection: collection.clone(),
+            references: vec![ReferencedCollection {
+                parent: "/".into(),
+                collection: CollectionData {
+                    id: "products".into(),
+                    documents: vec![],
+                },
+            }],
+        };
+        let plain_json = serde_json::to_string(&collection).unwrap();
+        let snapshot_json = serde_json::to_string(&snapshot).unwrap();
+
+        let plain = serde_json::from_str::<Snapshot>(&plain_json).unwrap();
+        assert_eq!(plain.collection, collection);
+        assert!(plain.references.is_empty());
+        let parsed = serde_json::from_str::<Snapshot>(&snapshot_json).unwrap();
+        assert_eq!(parsed.references.len(), 1);
+        assert_eq!(parsed.references[0].collection.id, "products");
+        assert_eq!(
+            serde_json::from_str::<CollectionData>(&snapshot_json).unwrap(),
+            collection
+        );
+        assert_eq!(
+            serde_json::to_string(&Snapshot {
+                collection: collection.clone(),
+                references: vec![],
+            })
+            .unwrap(),
+            plain_json
+        );
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+};
+
+use firestore_grpc::v1::{value::ValueType, Value, Write};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    collect::{collect_collection, collect_document_collections, get_documents},
+    delete::delete_documents,
+    rollback::{capture_prior_state, rollback, RollbackSnapshot},
+    seed::{
+        commit_writes, from_firestore_value, generate_remapped_writes, write_document_name,
+        CollectionData, DocumentData, PathRemap, SeedOptions,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// Progress of a move, saved to a local file after every stage and removed once the move is done.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoveProgress {
+    /// Path of the moved collection or document relative to the database.
+    pub source: String,
+    /// Path the subtree is moved to, relative to the database.
+    pub destination: String,
+    pub stage: MoveStage,
+    /// Full names of the moved documents, parents first.
+    pub source_documents: Vec<String>,
+    /// State of the destination documents from before the copy.
+    pub destination_prior: RollbackSnapshot,
+    /// Number of source documents deleted so far.
+    pub deleted: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MoveStage {
+    /// The subtree is being copied, the source is intact.
+    Copying,
+    /// The copy has been verified, the source is intact.
+    Verified,
+    /// The source is being deleted.
+    Deleting,
+    /// The source has been deleted, the progress file is removed right after reaching this stage.
+    Done,
+}
+
+/// Result of a move.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MoveReport {
+    pub copied: usize,
+    pub deleted: usize,
+}
+
+/// Moves the collection or the document subtree to the destination path.
+///
+/// The subtree is copied (references pointing inside of it are rewritten), the copy is compared
+/// with the source, and only then the source is deleted. Progress is saved to `progress_path`;
+/// when the file exists, the move recorded in it is completed instead of starting over.
+/// An interrupted move can also be reverted with [`rollback_move`]. The file is removed once
+/// the move is done, so a finished move can't be reverted.
+pub async fn move_subtree(
+    conn: FirestoreConnection,
+    source: &str,
+    destination: &str,
+    progress_path: &str,
+) -> Result<MoveReport, BoxError> {
+    let source = source.trim_matches('/').to_string();
+    let destination = destination.trim_matches('/').to_string();
+    let source_segments = source.split('/').filter(|s| !s.is_empty()).count();
+    let destination_segments = destination.split('/').filter(|s| !s.is_empty()).count();
+    if source_segments == 0
+        || destination_segments == 0
+        || source_segments % 2 != destination_segments % 2
+    {
+        return Err(
+            "The source and the destination have to be both collections or both documents".into(),
+        );
+    }
+    let remap = move_remap(&source, &destination);
+
+    let mut progress = match load_progress(progress_path)? {
+        Some(progress) if progress.source == source && progress.destination == destination => {
+            progress
+        }
+        Some(_) => {
+            return Err(format!(
+                "{} records another move, complete or roll it back first",
+                progress_path
+            )
+            .into())
+        }
+        None => MoveProgress {
+            source: source.clone(),
+            destination: destination.clone(),
+            stage: MoveStage::Copying,
+            source_documents: vec![],
+            destination_prior: RollbackSnapshot::default(),
+            deleted: 0,
+        },
+    };
+
+    let mut report = MoveReport::default();
+    if progress.stage == MoveStage::Copying {
+        let writes = subtree_writes(conn.clone(), &source, &remap).await?;
+        if writes.is_empty() {
+            return Err(format!("There is nothing to move at {}", source).into());
+        }
+        if progress.source_documents.is_empty() {
+            // captured only once, a resumed copy would capture the partial copy
+            progress.destination_prior = capture_prior_state(conn.clone(), &writes).await?;
+        }
+        progress.source_documents = writes
+            .iter()
+            .map(|write| source_name(&conn.1, write_document_name(write), &source, &destination))
+            .collect();
+        save_progress(progress_path, &progress)?;
+
+        let database_path = conn.1.trim_end_matches("/documents").to_string();
+        commit_writes(
+            conn.clone(),
+            &writes,
+            &database_path,
+            &SeedOptions::default(),
+        )
+        .await?;
+        verify_copy(
+            conn.clone(),
+            &progress.source_documents,
+            &source,
+            &destination,
+            &remap,
+        )
+        .await?;
+        report.copied = writes.len();
+        progress.stage = MoveStage::Verified;
+        save_progress(progress_path, &progress)?;
+    }
+
+    if progress.stage == MoveStage::Verified || progress.stage == MoveStage::Deleting {
+        progress.stage = MoveStage::Deleting;
+        save_progress(progress_path, &progress)?;
+        let mut names = progress.source_documents.clone();
+        names.reverse();
+        let deleting = progress.clone();
+        report.deleted = delete_documents(conn, names, |deleted| {
+            let progress = MoveProgress {
+                deleted: deleted.deleted,
+                ..deleting.clone()
+            };
+            // the count is informative only, a resumed move deletes all documents again
+            let _ = save_progress(progress_path, &progress);
+        })
+        .await?;
+        progress.deleted = report.deleted;
+        progress.stage = MoveStage::Done;
+        save_progress(progress_path, &progress)?;
+        fs::remove_file(progress_path)?;
+    }
+    Ok(report)
+}
+
+/// Reverts the move recorded in the progress file.
+///
+/// Deleted source documents are restored from the verified copy and the destination documents
+/// are restored to their state from before the move. The progress file is removed afterwards.
+pub async fn rollback_move(
+    conn: FirestoreConnection,
+    progress_path: &str,
+) -> Result<MoveReport, BoxError> {
+    let progress = load_progress(progress_path)?
+        .ok_or_else(|| format!("There is no move recorded in {}", progress_path))?;
+    let mut report = MoveReport::default();
+
+    if progress.stage == MoveStage::Deleting || progress.stage == MoveStage::Done {
+        let reverse_remap = move_remap(&progress.destination, &progress.source);
+        let source_documents = progress.source_documents.iter().collect::<HashSet<_>>();
+        let writes = subtree_writes(conn.clone(), &progress.destination, &reverse_remap)
+            .await?
+            .into_iter()
+            .filter(|write| source_documents.contains(&write_document_name(write).to_string()))
+            .collect::<Vec<_>>();
+        let database_path = conn.1.trim_end_matches("/documents").to_string();
+        commit_writes(
+            conn.clone(),
+            &writes,
+            &database_path,
+            &SeedOptions::default(),
+        )
+        .await?;
+        report.copied = writes.len();
+    }
+    rollback(conn, &progress.destination_prior).await?;
+    fs::remove_file(progress_path)?;
+    Ok(report)
+}
+
+/// Generates the writes copying the subtree found on the path, rewritten with the remapping rules.
+async fn subtree_writes(
+    conn: FirestoreConnection,
+    path: &str,
+    remap: &PathRemap,
+) -> Result<Vec<Write>, BoxError> {
+    let full_path = format!("{}/{}", conn.1, path);
+    let (collection_path, id) = full_path.rsplit_once('/').unwrap_or_default();
+    if path.split('/').count() % 2 == 1 {
+        let collection = collect_collection(conn.clone(), full_path.clone()).await?;
+        return Ok(generate_remapped_writes(
+            &collection,
+            collection_path,
+            remap,
+        ));
+    }
+
+    let document = get_documents(conn.clone(), vec![full_path.clone()])
+        .await?
+        .remove(&full_path);
+    let subcollections = collect_document_collections(conn, &full_path).await?;
+    if document.is_none() && subcollections.is_none() {
+        return Ok(vec![]);
+    }
+    let (parent_path, collection_id) = collection_path.rsplit_once('/').unwrap_or_default();
+    let collection = CollectionData {
+        id: collection_id.to_string(),
+        documents: vec![DocumentData {
+            id: id.to_string(),
+            data: document
+                .map(|document| {
+                    document
+                        .fields
+                        .into_iter()
+                        .filter_map(|(key, value)| value.value_type.map(|value| (key, value)))
+                        .map(|(key, value)| (key, from_firestore_value(value)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            subcollections,
+        }],
+    };
+    Ok(generate_remapped_writes(&collection, parent_path, remap))
+}
+
+/// Checks that every source document is stored at the destination with the same fields.
+///
+/// The source documents are read again and compared with their copies, references in the source
+/// fields are rewritten with the remapping rules first. Source documents which only hold
+/// subcollections are copied as empty documents.
+async fn verify_copy(
+    conn: FirestoreConnection,
+    source_documents: &[String],
+    source: &str,
+    destination: &str,
+    remap: &PathRemap,
+) -> Result<(), BoxError> {
+    let copied_names = source_documents
+        .iter()
+        .map(|name| source_name(&conn.1, name, destination, source))
+        .collect::<Vec<_>>();
+    let sources = get_documents(conn.clone(), source_documents.to_vec()).await?;
+    let copies = get_documents(conn, copied_names.clone()).await?;
+    for (source_name, copied_name) in source_documents.iter().zip(copied_names) {
+        let expected = sources
+            .get(source_name)
+            .map(|document| remap_fields(&document.fields, remap))
+            .unwrap_or_default();
+        match copies.get(&copied_name) {
+            Some(copy) if copy.fields == expected => {}
+            _ => {
+                return Err(format!(
+                    "The copy of {} does not match the source, the source is left intact",
+                    source_name
+                )
+                .into())
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the references in the fields with the remapping rules.
+fn remap_fields(fields: &HashMap<String, Value>, remap: &PathRemap) -> HashMap<String, Value> {
+    fields
+        .iter()
+        .map(|(key, value)| (key.clone(), remap_value(value, remap)))
+        .collect()
+}
+
+fn remap_value(value: &Value, remap: &PathRemap) -> Value {
+    let value_type = value
+        .value_type
+        .as_ref()
+        .map(|value_type| match value_type {
+            ValueType::ReferenceValue(path) => ValueType::ReferenceValue(remap.apply(path)),
+            ValueType::ArrayValue(array) => {
+                let mut array = array.clone();
+                array.values = array
+                    .values
+                    .iter()
+                    .map(|value| remap_value(value, remap))
+                    .collect();
+                ValueType::ArrayValue(array)
+            }
+            ValueType::MapValue(map) => {
+                let mut map = map.clone();
+                map.fields = remap_fields(&map.fields, remap);
+                ValueType::MapValue(map)
+            }
+            other => other.clone(),
+        });
+    Value { value_type }
+}
+
+fn move_remap(source: &str, destination: &str) -> PathRemap {
+    PathRemap {
+        prefixes: vec![(source.to_string(), destination.to_string())],
+        ..PathRemap::default()
+    }
+}
+
+/// Maps the full name of a copied document back to the name of its source.
+fn source_name(base_path: &str, name: &str, source: &str, destination: &str) -> String {
+    let relative = name
+        .strip_prefix(base_path)
+        .unwrap_or(name)
+        .trim_start_matches('/');
+    let relative = match relative.strip_prefix(destination) {
+        Some(rest) => format!("{}{}", source, rest),
+        None => relative.to_string(),
+    };
+    format!("{}/{}", base_path, relative)
+}
+
+fn load_progress(path: &str) -> Result<Option<MoveProgress>, BoxError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn save_progress(path: &str, progress: &MoveProgress) -> Result<(), BoxError> {
+    fs::write(path, serde_json::to_string_pretty(progress)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use firestore_grpc::v1::ArrayValue;
+
+    use super::*;
+
+    #[test]
+    fn test_source_name() {
+        let base = "projects/p/databases/(default)/documents";
+        let remap = move_remap("orders", "users/u1/orders");
+        let source = format!("{}/orders/o1/items/i1", base);
+        let copied = remap.apply(&source);
+
+        assert_eq!(copied, format!("{}/users/u1/orders/o1/items/i1", base));
+        assert_eq!(
+            source_name(base, &copied, "orders", "users/u1/orders"),
+            source
+        );
+    }
+
+    #[test]
+    fn test_remap_value() {
+        let base = "projects/p/databases/(default)/documents";
+        let remap = move_remap("orders", "archive");
+        let reference = |path: &str| Value {
+            value_type: Some(ValueType::ReferenceValue(format!("{}/{}", base, path))),
+        };
+        let value = Value {
+            value_type: Some(ValueType::ArrayValue(ArrayValue {
+                values: vec![reference("orders/o1"), reference("users/u1")],
+            })),
+        };
+
+        assert_eq!(
+            remap_value(&value, &remap),
+            Value {
+                value_type: Some(ValueType::ArrayValue(ArrayValue {
+                    values: vec![reference("archive/o1"), reference("users/u1")],
+                })),
+            }
+        );
+    }
+}
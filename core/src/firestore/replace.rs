@@ -0,0 +1,173 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use serde::Serialize;
+
+use super::{
+    collect::collect_collection,
+    delete::delete_documents,
+    rollback::capture_documents,
+    seed::{
+        document_names_for_collection, generate_remapped_writes, parent_document_full_path,
+        seed_collection_with_options, validate_document_path, write_document_name, CollectionData,
+        SeedError, SeedOptions, SeedReport,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// Result of replacing a collection with a snapshot.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplaceReport {
+    /// Set when nothing has been written and the report is only a preview.
+    pub dry_run: bool,
+    /// Documents of the snapshot which are written.
+    pub writes: Vec<String>,
+    /// Documents found in Firestore but absent from the snapshot, which are deleted.
+    pub deletes: Vec<String>,
+    /// Result of seeding the snapshot. Empty in a dry run.
+    pub seed: SeedReport,
+    /// Number of deleted documents. Zero in a dry run.
+    pub deleted: usize,
+}
+
+/// Makes the collection in Firestore mirror the snapshot.
+///
+/// The whole target collection is listed recursively, the snapshot is seeded
+/// and every document (including documents of subcollections) which is not part of the snapshot is deleted.
+/// With `dry_run` set only the preview of the changes is returned.
+/// The IDs are never regenerated, [`SeedOptions::regenerate_ids`] is ignored,
+/// as the documents of the snapshot replace the documents with the same IDs.
+/// With [`SeedOptions::undo_log`] set the prior state of both the written and the deleted documents
+/// is saved before anything is changed.
+pub async fn replace_collection(
+    conn: FirestoreConnection,
+    collection: &CollectionData,
+    parent_document_path: &str,
+    options: &SeedOptions,
+    dry_run: bool,
+) -> Result<ReplaceReport, SeedError> {
+    if !validate_document_path(parent_document_path) {
+        return Err(SeedError::InvalidPath);
+    }
+    let parent_path = parent_document_full_path(&conn.1, parent_document_path);
+    // the snapshot is written where the remapping rules move it
+    let collection_path = options
+        .remap
+        .apply_prefixes(&format!("{}/{}", parent_path, collection.id));
+    let (target_parent_path, _) = collection_path.rsplit_once('/').unwrap_or_default();
+    let target_parent_path = target_parent_path.to_string();
+
+    let existing = collect_collection(conn.clone(), collection_path)
+        .await
+        .map_err(SeedError::FirestoreClientError)?;
+    let writes = generate_remapped_writes(collection, &parent_path, &options.remap)
+        .iter()
+        .map(|write| write_document_name(write).to_string())
+        .collect::<Vec<_>>();
+    let deletes = names_to_delete(
+        document_names_for_collection(&existing, &target_parent_path),
+        &writes,
+    );
+
+    let mut report = ReplaceReport {
+        dry_run,
+        writes,
+        deletes,
+        ..ReplaceReport::default()
+    };
+    if dry_run {
+        return Ok(report);
+    }
+
+    let options = &SeedOptions {
+        regenerate_ids: false,
+        ..options.clone()
+    };
+    let mut seed_options = options.clone();
+    if let Some(undo_log) = seed_options.undo_log.take() {
+        if !(options.resume && Path::new(&undo_log).exists()) {
+            let names = [report.writes.as_slice(), report.deletes.as_slice()].concat();
+            save_prior_state(conn.clone(), &names, &undo_log)
+                .await
+                .map_err(SeedError::FirestoreClientError)?;
+        }
+    }
+    report.seed = seed_collection_with_options(
+        conn.clone(),
+        collection,
+        parent_document_path,
+        &seed_options,
+    )
+    .await?;
+    report.deleted = delete_documents(conn, report.deletes.clone(), |_| {})
+        .await
+        .map_err(SeedError::FirestoreClientError)?;
+    Ok(report)
+}
+
+async fn save_prior_state(
+    conn: FirestoreConnection,
+    names: &[String],
+    path: &str,
+) -> Result<(), BoxError> {
+    let prior_state = capture_documents(conn, names).await?;
+    fs::write(path, serde_json::to_string_pretty(&prior_state)?)?;
+    Ok(())
+}
+
+fn names_to_delete(existing: Vec<String>, kept: &[String]) -> Vec<String> {
+    let kept = kept.iter().collect::<HashSet<_>>();
+    existing
+        .into_iter()
+        .filter(|name| !kept.contains(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::seed::DocumentData;
+    use std::collections::HashMap;
+
+    fn document(id: &str, subcollections: Option<Vec<CollectionData>>) -> DocumentData {
+        DocumentData {
+            id: id.to_string(),
+            data: HashMap::new(),
+            subcollections,
+        }
+    }
+
+    #[test]
+    fn test_names_to_delete() {
+        let snapshot = CollectionData {
+            id: "users".into(),
+            documents: vec![document("a", None), document("b", None)],
+        };
+        let existing = CollectionData {
+            id: "users".into(),
+            documents: vec![
+                document(
+                    "a",
+                    Some(vec![CollectionData {
+                        id: "orders".into(),
+                        documents: vec![document("o1", None)],
+                    }]),
+                ),
+                document("c", None),
+            ],
+        };
+        let base = "projects/p/databases/(default)/documents";
+
+        let deletes = names_to_delete(
+            document_names_for_collection(&existing, base),
+            &document_names_for_collection(&snapshot, base),
+        );
+
+        assert_eq!(
+            deletes,
+            vec![
+                format!("{}/users/a/orders/o1", base),
+                format!("{}/users/c", base),
+            ]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use firestore_grpc::tonic::{Code, Status};
+use rand::Rng;
+use tokio::time::sleep;
+
+/// Policy of retrying Firestore calls which failed with a transient error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts of a single call, including the first one.
+    pub max_attempts: usize,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts.
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after every retry.
+    pub multiplier: f64,
+    /// Fraction of the delay randomly added or subtracted, between `0.0` and `1.0`.
+    pub jitter: f64,
+    /// Status codes which are worth retrying.
+    pub retriable_codes: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            retriable_codes: vec![
+                Code::Unavailable,
+                Code::Aborted,
+                Code::ResourceExhausted,
+                Code::DeadlineExceeded,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy making a single attempt of every call.
+    pub fn no_retries() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Parses the canonical name of a gRPC status code, e.g. `UNAVAILABLE` or `resource_exhausted`.
+    pub fn parse_code(name: &str) -> Result<Code, String> {
+        let code = match name.trim().to_uppercase().as_str() {
+            "OK" => Code::Ok,
+            "CANCELLED" => Code::Cancelled,
+            "UNKNOWN" => Code::Unknown,
+            "INVALID_ARGUMENT" => Code::InvalidArgument,
+            "DEADLINE_EXCEEDED" => Code::DeadlineExceeded,
+            "NOT_FOUND" => Code::NotFound,
+            "ALREADY_EXISTS" => Code::AlreadyExists,
+            "PERMISSION_DENIED" => Code::PermissionDenied,
+            "RESOURCE_EXHAUSTED" => Code::ResourceExhausted,
+            "FAILED_PRECONDITION" => Code::FailedPrecondition,
+            "ABORTED" => Code::Aborted,
+            "OUT_OF_RANGE" => Code::OutOfRange,
+            "UNIMPLEMENTED" => Code::Unimplemented,
+            "INTERNAL" => Code::Internal,
+            "UNAVAILABLE" => Code::Unavailable,
+            "DATA_LOSS" => Code::DataLoss,
+            "UNAUTHENTICATED" => Code::Unauthenticated,
+            _ => return Err(format!("Unknown status code {}", name)),
+        };
+        Ok(code)
+    }
+
+    pub(crate) fn is_retriable(&self, status: &Status) -> bool {
+        self.is_retriable_code(status.code())
+    }
+
+    pub(crate) fn is_retriable_code(&self, code: Code) -> bool {
+        self.retriable_codes.contains(&code)
+    }
+
+    /// Delay before the given retry (counted from 1), without the jitter.
+    fn backoff(&self, retry: usize) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1) as i32);
+        self.initial_backoff.mul_f64(factor).min(self.max_backoff)
+    }
+
+    pub(crate) fn backoff_with_jitter(&self, retry: usize) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+        self.backoff(retry).mul_f64(factor)
+    }
+}
+
+/// Retry policy of a connection together with the number of retries made with it.
+///
+/// Clones share the counter, use [`FirestoreConnection::scoped_retries`](super::FirestoreConnection::scoped_retries)
+/// to count the retries of a single operation.
+#[derive(Debug, Clone, Default)]
+pub struct Retrier {
+    policy: Arc<RetryPolicy>,
+    retries: Arc<AtomicUsize>,
+}
+
+impl Retrier {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Retrier {
+            policy: Arc::new(policy),
+            retries: Arc::default(),
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Number of retries made so far.
+    pub fn retries(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// A retrier with the same policy and its own counter.
+    pub(crate) fn scoped(&self) -> Self {
+        Retrier {
+            policy: self.policy.clone(),
+            retries: Arc::default(),
+        }
+    }
+
+    /// Runs the call until it succeeds, fails with a non-retriable status
+    /// or the attempts of the policy are exhausted.
+    pub(crate) async fn call<T, F, Fut>(&self, call: F) -> Result<T, Status>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        self.call_retrying(|status| self.policy.is_retriable(status), call)
+            .await
+    }
+
+    /// Runs the call like [`call`](Self::call), but retries only the failures accepted by `should_retry`.
+    pub(crate) async fn call_retrying<T, R, F, Fut>(
+        &self,
+        should_retry: R,
+        mut call: F,
+    ) -> Result<T, Status>
+    where
+        R: Fn(&Status) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut attempts = 1;
+        loop {
+            match call().await {
+                Err(status) if attempts < self.policy.max_attempts && should_retry(&status) => {
+                    let delay = self.policy.backoff_with_jitter(attempts);
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    attempts += 1;
+                    sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            multiplier: 3.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(300));
+        assert_eq!(policy.backoff(3), Duration::from_millis(900));
+        assert_eq!(policy.backoff(4), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_backoff_with_jitter() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.backoff_with_jitter(1);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn test_is_retriable() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retriable(&Status::unavailable("")));
+        assert!(policy.is_retriable(&Status::aborted("")));
+        assert!(!policy.is_retriable(&Status::not_found("")));
+        assert!(policy.is_retriable_code(Code::ResourceExhausted));
+        assert!(!policy.is_retriable_code(Code::AlreadyExists));
+    }
+
+    #[test]
+    fn test_parse_code() {
+        assert_eq!(
+            RetryPolicy::parse_code("UNAVAILABLE"),
+            Ok(Code::Unavailable)
+        );
+        assert_eq!(
+            RetryPolicy::parse_code(" resource_exhausted "),
+            Ok(Code::ResourceExhausted)
+        );
+        assert!(RetryPolicy::parse_code("UNAVAILABLE,ABORTED").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use firestore_grpc::v1::Write;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    collect::get_documents,
+    seed::{
+        commit_writes, delete_write, generate_writes_for_collection, write_document_name,
+        CollectionData, DocumentData, SeedOptions, ValueType,
+    },
+    type_mapping::from_firestore_value,
+    BoxError, FirestoreConnection,
+};
+
+/// State of the documents targeted by a seed, captured before they were written.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RollbackSnapshot {
+    pub documents: Vec<PriorDocument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriorDocument {
+    /// Full name of the document.
+    pub name: String,
+    /// Fields of the document, `None` when the document did not exist.
+    pub data: Option<HashMap<String, ValueType>>,
+}
+
+/// Result of a rollback.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RollbackReport {
+    /// Number of documents restored to their prior content.
+    pub restored: usize,
+    /// Number of documents deleted because they did not exist before.
+    pub deleted: usize,
+}
+
+/// Fetches the current version of every document targeted by the writes.
+pub(crate) async fn capture_prior_state(
+    conn: FirestoreConnection,
+    writes: &[Write],
+) -> Result<RollbackSnapshot, BoxError> {
+    let names = writes
+        .iter()
+        .map(|write| write_document_name(write).to_string())
+        .collect::<Vec<_>>();
+    capture_documents(conn, &names).await
+}
+
+/// Fetches the current version of every document with the given full names.
+pub(crate) async fn capture_documents(
+    conn: FirestoreConnection,
+    document_names: &[String],
+) -> Result<RollbackSnapshot, BoxError> {
+    let mut existing = get_documents(conn, document_names.to_vec()).await?;
+    let documents = document_names
+        .iter()
+        .map(|name| PriorDocument {
+            name: name.clone(),
+            data: existing.remove(name).map(|document| {
+                document
+                    .fields
+                    .into_iter()
+                    .filter_map(|(key, value)| value.value_type.map(|value| (key, value)))
+                    .map(|(key, value)| (key, from_firestore_value(value)))
+                    .collect()
+            }),
+        })
+        .collect();
+    Ok(RollbackSnapshot { documents })
+}
+
+/// Restores the documents to the state recorded in the snapshot.
+///
+/// Documents which existed are overwritten with their prior content,
+/// documents which did not exist are deleted.
+pub async fn rollback(
+    conn: FirestoreConnection,
+    snapshot: &RollbackSnapshot,
+) -> Result<RollbackReport, BoxError> {
+    let writes = rollback_writes(snapshot);
+    let database_path = conn.1.trim_end_matches("/documents").to_string();
+    commit_writes(conn, &writes, &database_path, &SeedOptions::default()).await?;
+    let deleted = snapshot
+        .documents
+        .iter()
+        .filter(|document| document.data.is_none())
+        .count();
+    Ok(RollbackReport {
+        restored: snapshot.documents.len() - deleted,
+        deleted,
+    })
+}
+
+fn rollback_writes(snapshot: &RollbackSnapshot) -> Vec<Write> {
+    snapshot
+        .documents
+        .iter()
+        .flat_map(|document| match &document.data {
+            Some(data) => {
+                let (collection_path, id) = document.name.rsplit_once('/').unwrap_or_default();
+                let (parent_path, collection_id) =
+                    collection_path.rsplit_once('/').unwrap_or_default();
+                let collection = CollectionData {
+                    id: collection_id.to_string(),
+                    documents: vec![DocumentData {
+                        id: id.to_string(),
+                        data: data.clone(),
+                        subcollections: None,
+                    }],
+                };
+                generate_writes_for_collection(&collection, parent_path)
+            }
+            None => vec![delete_write(document.name.clone())],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firestore_grpc::v1::write::Operation;
+
+    #[test]
+    fn test_rollback_writes() {
+        let snapshot = RollbackSnapshot {
+            documents: vec![
+                PriorDocument {
+                    name: "projects/p/databases/d/documents/users/a/posts/1".into(),
+                    data: Some(
+                        vec![("title".to_string(), ValueType::StringValue("old".into()))]
+                            .into_iter()
+                            .collect(),
+                    ),
+                },
+                PriorDocument {
+                    name: "projects/p/databases/d/documents/users/b".into(),
+                    data: None,
+                },
+            ],
+        };
+
+        let writes = rollback_writes(&snapshot);
+
+        assert_eq!(writes.len(), 2);
+        match &writes[0].operation {
+            Some(Operation::Update(document)) => {
+                assert_eq!(document.name, snapshot.documents[0].name);
+                assert!(document.fields.contains_key("title"));
+            }
+            other => panic!("Unexpected operation {:?}", other),
+        }
+        assert_eq!(
+            writes[1].operation,
+            Some(Operation::Delete(snapshot.documents[1].name.clone()))
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use firestore_grpc::v1::Document;
+use futures::future::try_join_all;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    collect::{collect_document, get_documents, list_document_ids},
+    hash::Fnv1a,
+    references::{follow_references, ReferencedDocuments},
+    seed::CollectionData,
+    transform::DocumentTransformer,
+    BoxError, FirestoreConnection,
+};
+
+/// Options of a sampling export.
+#[derive(Debug, Clone, Default)]
+pub struct SampleOptions {
+    /// Share of the documents of the collection included in the sample, between `0.0` and `1.0`.
+    pub fraction: f64,
+    /// When set, documents are picked by a hash of the seed and their IDs instead of randomly,
+    /// so the same seed picks the same documents on every run.
+    pub seed: Option<u64>,
+    /// Include the documents referenced from the sample, so that it has no dangling references.
+    pub follow_references: bool,
+}
+
+/// Documents picked from a collection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    /// The picked documents together with their subcollections.
+    pub collection: CollectionData,
+    /// Documents of other collections referenced from the picked documents.
+    pub referenced: ReferencedDocuments,
+}
+
+/// Exports a subset of the documents of the collection together with their subcollections.
+///
+/// Only IDs of the documents are listed for the whole collection, fields and subcollections
+/// are fetched for the picked documents only.
+pub async fn sample_collection(
+    conn: FirestoreConnection,
+    full_path: String,
+    options: &SampleOptions,
+) -> Result<Sample, BoxError> {
+    sample(conn, full_path, options, None).await
+}
+
+/// Samples the collection like [`sample_collection`], passing every picked document through
+/// the transformer before its subcollections are collected and its references are followed.
+///
+/// The referenced documents are passed through the transformer as well.
+pub async fn sample_collection_transformed(
+    conn: FirestoreConnection,
+    full_path: String,
+    options: &SampleOptions,
+    transformer: &dyn DocumentTransformer,
+) -> Result<Sample, BoxError> {
+    sample(conn, full_path, options, Some(transformer)).await
+}
+
+async fn sample(
+    conn: FirestoreConnection,
+    full_path: String,
+    options: &SampleOptions,
+    transformer: Option<&dyn DocumentTransformer>,
+) -> Result<Sample, BoxError> {
+    let (parent_path, collection_id) = full_path
+        .rsplit_once('/')
+        .ok_or_else(|| format!("Invalid collection path {}", full_path))?;
+    if !(0.0..=1.0).contains(&options.fraction) {
+        return Err(format!(
+            "The sampled fraction has to be between 0 and 1, got {}",
+            options.fraction
+        )
+        .into());
+    }
+    let ids = list_document_ids(conn.clone(), parent_path, collection_id).await?;
+    let names = {
+        // the generator is not `Send`, it has to be dropped before the next await
+        let mut rng = rand::thread_rng();
+        ids.into_iter()
+            .filter(|id| match options.seed {
+                Some(seed) => is_picked(seed, id, options.fraction),
+                None => rng.gen::<f64>() < options.fraction,
+            })
+            .map(|id| format!("{}/{}", full_path, id))
+            .collect::<Vec<_>>()
+    };
+
+    let mut found = get_documents(conn.clone(), names.clone()).await?;
+    // documents which only have subcollections are not found, but they are sampled as well
+    let documents = names.into_iter().map(|name| {
+        found.remove(&name).unwrap_or(Document {
+            name,
+            fields: Default::default(),
+            create_time: None,
+            update_time: None,
+        })
+    });
+    let documents = try_join_all(
+        documents.map(|document| collect_document(conn.clone(), document, transformer)),
+    )
+    .await?;
+    let mut collection = CollectionData {
+        id: collection_id.to_string(),
+        documents: documents.into_iter().flatten().collect(),
+    };
+
+    let referenced = if options.follow_references {
+        follow_references(conn, &mut collection, &full_path, None, transformer).await?
+    } else {
+        ReferencedDocuments::default()
+    };
+    Ok(Sample {
+        collection,
+        referenced,
+    })
+}
+
+/// Decides deterministically whether the document is sampled.
+fn is_picked(seed: u64, id: &str, fraction: f64) -> bool {
+    let mut hasher = Fnv1a::default();
+    hasher.write(&seed.to_be_bytes());
+    hasher.write(id.as_bytes());
+    (hasher.finish() as f64 / u64::MAX as f64) < fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_picked() {
+        let ids = (0..10_000)
+            .map(|index| format!("user-{}", index))
+            .collect::<Vec<_>>();
+        let picked = |seed, fraction| {
+            ids.iter()
+                .filter(|id| is_picked(seed, id, fraction))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        let sample = picked(7, 0.01);
+        assert!((50..150).contains(&sample.len()));
+        assert_eq!(sample, picked(7, 0.01));
+        assert_ne!(sample, picked(8, 0.01));
+        assert!(sample.iter().all(|id| picked(7, 0.1).contains(id)));
+        assert!(picked(7, 0.0).is_empty());
+        assert_eq!(picked(7, 1.0).len(), ids.len());
+    }
+}
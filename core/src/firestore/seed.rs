@@ -1,9 +1,33 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    path::Path,
+    sync::Arc,
+};
 
-use firestore_grpc::v1::{write::Operation, BeginTransactionRequest, CommitRequest, Write};
+use firestore_grpc::tonic::{Code, Status};
+use firestore_grpc::v1::{
+    document_transform::{
+        field_transform::{ServerValue, TransformType},
+        FieldTransform,
+    },
+    precondition::ConditionType,
+    write::Operation,
+    ArrayValue, BeginTransactionRequest, CommitRequest, DocumentMask, Precondition, Write,
+};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    stream, StreamExt,
+};
+use serde::Serialize;
+use tokio::sync::Mutex;
 
 pub use super::type_mapping::*;
-use super::{type_mapping::to_firestore_value, FirestoreConnection};
+use super::{
+    collect::get_documents, ids::regenerate_ids, journal::SeedJournal,
+    rollback::capture_prior_state, throttle::RampUpLimiter, FirestoreConnection,
+};
 
 pub type BoxError = Box<dyn std::error::Error + Sync + Send + 'static>;
 
@@ -33,152 +57,846 @@ impl std::error::Error for SeedError {
     }
 }
 
+/// Options controlling how the documents of a snapshot are written.
+#[derive(Debug, Clone, Default)]
+pub struct SeedOptions {
+    /// When set, every write carries an update mask built from the fields of the snapshot,
+    /// so fields of existing documents which are not mentioned in the snapshot are preserved.
+    pub merge: bool,
+    /// Condition the target documents have to meet for the writes to be applied.
+    pub precondition: SeedPrecondition,
+    /// Maximum number of batches committed at the same time.
+    /// `0` and `1` commit the batches one after another.
+    pub concurrency: usize,
+    /// Path of a local file recording the committed batches.
+    pub journal: Option<String>,
+    /// Skip the batches already recorded in the journal instead of starting a fresh one.
+    pub resume: bool,
+    /// Path of a local file the prior state of the targeted documents is saved to before writing,
+    /// see [`rollback`](super::rollback::rollback).
+    pub undo_log: Option<String>,
+    /// Rules rewriting the names of the written documents and the references they contain.
+    pub remap: PathRemap,
+    /// Give every document a fresh auto-ID, rewriting references to documents of the snapshot.
+    ///
+    /// Documents are only created, a collision with an existing document is reported as skipped
+    /// together with the documents of its subcollections. It can't be combined with `resume`,
+    /// as the IDs of the interrupted run are not saved.
+    pub regenerate_ids: bool,
+}
+
+/// Requirement on the existence of a document before it is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedPrecondition {
+    /// Documents are written regardless of their current state.
+    #[default]
+    None,
+    /// Only documents which do not exist yet are written (create-only).
+    MustNotExist,
+    /// Only documents which already exist are written (update-only).
+    MustExist,
+}
+
+/// Summary of a seed operation.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeedReport {
+    /// Number of documents written to Firestore.
+    pub written: usize,
+    /// Documents which were not written because of the precondition.
+    pub skipped: Vec<SkippedDocument>,
+    /// Number of Firestore calls retried after a transient error.
+    pub retries: usize,
+    /// Number of batches skipped because the journal records them as committed.
+    pub resumed_batches: usize,
+    /// Full names of the documents of the snapshot mapped to the names they were written with,
+    /// when the IDs are regenerated.
+    pub id_mapping: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedDocument {
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum SkipReason {
+    /// The document exists, but it was expected to be missing.
+    AlreadyExists,
+    /// The document is missing, but it was expected to exist.
+    Missing,
+    /// Firestore rejected the document because of a failed precondition.
+    /// It happens when the document was created or deleted after the existence check.
+    /// A rejected batch is split and committed again, so that only the rejected document is skipped.
+    Rejected(String),
+    /// One of the parent documents was skipped while the IDs were regenerated,
+    /// the document would end up under a document the seed did not create.
+    ParentSkipped,
+}
+
 pub async fn seed_collection(
     conn: FirestoreConnection,
     collection: &CollectionData,
     parent_document_path: &str,
 ) -> Result<usize, SeedError> {
+    seed_collection_with_options(
+        conn,
+        collection,
+        parent_document_path,
+        &SeedOptions::default(),
+    )
+    .await
+    .map(|report| report.written)
+}
+
+pub async fn seed_collection_with_options(
+    conn: FirestoreConnection,
+    collection: &CollectionData,
+    parent_document_path: &str,
+    options: &SeedOptions,
+) -> Result<SeedReport, SeedError> {
     if !validate_document_path(parent_document_path) {
         return Err(SeedError::InvalidPath);
     }
-    let trimmed_parent_path = parent_document_path.trim_matches('/');
+    let conn = conn.scoped_retries();
     let conn_clone = conn.clone();
-    let FirestoreConnection(_, base_path) = conn;
+    let FirestoreConnection(_, base_path, _) = conn;
     let database_path = base_path.trim_end_matches("/documents");
     seed_collection_in_transaction(
         conn_clone,
         collection,
-        &format!("{}/{}", base_path, trimmed_parent_path),
-        &database_path,
+        &parent_document_full_path(&base_path, parent_document_path),
+        database_path,
+        options,
     )
     .await
-    .map_err(|e| SeedError::FirestoreClientError(e))
-    
+    .map_err(SeedError::FirestoreClientError)
 }
 
-const BATCH_UPDATE_MAX_SIZE: usize = 500;
+pub(crate) const BATCH_UPDATE_MAX_SIZE: usize = 500;
 async fn seed_collection_in_transaction(
     conn: FirestoreConnection,
     collection: &CollectionData,
     parent_path: &str,
     database_path: &str,
-) -> Result<usize, BoxError> {
-    let operations = generate_writes_for_collection(collection, parent_path);
+    options: &SeedOptions,
+) -> Result<SeedReport, BoxError> {
+    let (writes, id_mapping, options) = generate_seed_writes(collection, parent_path, options);
+    let mut report = commit_writes(conn, &writes, database_path, &options).await?;
+    report.id_mapping = id_mapping;
+    Ok(report)
+}
+
+/// Generates the writes of the snapshot following the options.
+///
+/// With [`SeedOptions::regenerate_ids`] set the IDs are replaced and the returned options
+/// only allow creating documents.
+pub(crate) fn generate_seed_writes(
+    collection: &CollectionData,
+    parent_path: &str,
+    options: &SeedOptions,
+) -> (Vec<Write>, HashMap<String, String>, SeedOptions) {
+    let mut writes = generate_remapped_writes(collection, parent_path, &options.remap);
+    if !options.regenerate_ids {
+        return (writes, HashMap::new(), options.clone());
+    }
+    let id_mapping = regenerate_ids(&mut writes);
+    let options = SeedOptions {
+        precondition: SeedPrecondition::MustNotExist,
+        ..options.clone()
+    };
+    (writes, id_mapping, options)
+}
+
+/// Commits the writes in batches of at most [`BATCH_UPDATE_MAX_SIZE`], one transaction per batch.
+///
+/// Up to [`SeedOptions::concurrency`] batches are committed at the same time, but documents
+/// are only written after all documents closer to the root, see [`ordered_batches`].
+/// The overall rate of writes follows the ramp-up rule of [`RampUpLimiter`].
+///
+/// With [`SeedOptions::journal`] set every committed batch is recorded in the journal,
+/// and with [`SeedOptions::resume`] the batches recorded by a previous run are skipped.
+/// With [`SeedOptions::undo_log`] set the prior state of the documents is saved before anything is written.
+/// A resumed seed keeps the undo log of the interrupted run, which captured the state from before it.
+/// With [`SeedOptions::regenerate_ids`] set the subtrees of skipped documents are skipped as well.
+pub(crate) async fn commit_writes(
+    conn: FirestoreConnection,
+    writes: &[Write],
+    database_path: &str,
+    options: &SeedOptions,
+) -> Result<SeedReport, BoxError> {
+    commit_writes_with_limiter(conn, writes, database_path, options, RampUpLimiter::new()).await
+}
+
+/// Commits the writes like [`commit_writes`], sharing the limiter with earlier calls,
+/// so that writes split across calls keep ramping up instead of starting over.
+pub(crate) async fn commit_writes_with_limiter(
+    conn: FirestoreConnection,
+    writes: &[Write],
+    database_path: &str,
+    options: &SeedOptions,
+    limiter: RampUpLimiter,
+) -> Result<SeedReport, BoxError> {
+    if options.resume && options.regenerate_ids {
+        return Err(
+            "A seed with regenerated IDs can't be resumed, the IDs of the interrupted run are not saved"
+                .into(),
+        );
+    }
+    if let Some(undo_log) = &options.undo_log {
+        if !(options.resume && Path::new(undo_log).exists()) {
+            let prior_state = capture_prior_state(conn.clone(), writes).await?;
+            fs::write(undo_log, serde_json::to_string_pretty(&prior_state)?)?;
+        }
+    }
+    let mut report = SeedReport::default();
+    let levels = ordered_batches(writes);
+    let journal = match &options.journal {
+        Some(path) => {
+            let batches = levels.iter().flatten().cloned().collect::<Vec<_>>();
+            let journal = SeedJournal::open(path, &batches, options).await?;
+            Some(Arc::new(Mutex::new(journal)))
+        }
+        None => None,
+    };
+
+    let mut batch_index = 0;
+    let mut skipped_names = HashSet::new();
+    for level in levels {
+        let mut pending = Vec::new();
+        for batch in level {
+            let batch = if options.regenerate_ids {
+                let (batch, orphaned) = batch.into_iter().partition::<Vec<_>, _>(|write| {
+                    !has_skipped_parent(write_document_name(write), &skipped_names)
+                });
+                report
+                    .skipped
+                    .extend(orphaned.iter().map(|write| SkippedDocument {
+                        path: write_document_name(write).to_string(),
+                        reason: SkipReason::ParentSkipped,
+                    }));
+                batch
+            } else {
+                batch
+            };
+            let committed = match &journal {
+                Some(journal) => journal.lock().await.is_committed(batch_index),
+                None => false,
+            };
+            if committed {
+                report.resumed_batches += 1;
+            } else {
+                pending.push((batch_index, batch));
+            }
+            batch_index += 1;
+        }
+        let batch_reports = stream::iter(pending)
+            .map(|(index, batch)| {
+                let journal = journal.clone();
+                let batch_report = commit_batch(
+                    conn.clone(),
+                    batch,
+                    database_path.to_string(),
+                    options.clone(),
+                    limiter.clone(),
+                );
+                async move {
+                    let batch_report = batch_report.await?;
+                    if let Some(journal) = journal {
+                        journal.lock().await.record(index).await?;
+                    }
+                    Ok::<_, BoxError>(batch_report)
+                }
+            })
+            .buffer_unordered(options.concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await;
+        for batch_report in batch_reports {
+            let batch_report = batch_report?;
+            report.written += batch_report.written;
+            report.skipped.extend(batch_report.skipped);
+        }
+        skipped_names.extend(report.skipped.iter().map(|skipped| skipped.path.clone()));
+    }
+    report.retries = conn.2.retries();
+    Ok(report)
+}
 
-    for batch in operations.chunks(BATCH_UPDATE_MAX_SIZE) {
-        let transaction = begin_transaction(conn.clone(), database_path).await?;
+/// Checks whether any of the parent documents of the document is among the skipped names.
+pub(crate) fn has_skipped_parent(name: &str, skipped_names: &HashSet<String>) -> bool {
+    let mut path = name;
+    // every step up removes the ID of a document and the ID of its collection
+    while let Some((collection_path, _)) = path.rsplit_once('/') {
+        match collection_path.rsplit_once('/') {
+            Some((parent, _)) if skipped_names.contains(parent) => return true,
+            Some((parent, _)) => path = parent,
+            None => return false,
+        }
+    }
+    false
+}
 
-        commit_transaction(conn.clone(), Vec::from(batch), transaction, database_path).await?;
+/// Splits the writes into levels of batches of at most [`BATCH_UPDATE_MAX_SIZE`] writes.
+///
+/// A level groups documents of the same depth, so that parent documents are written
+/// before their subcollections while batches of a single level can be committed concurrently.
+pub(crate) fn ordered_batches(writes: &[Write]) -> Vec<Vec<Vec<Write>>> {
+    let mut levels = Vec::<(usize, Vec<Write>)>::new();
+    for write in writes {
+        let depth = write_document_name(write).matches('/').count();
+        match levels
+            .iter_mut()
+            .find(|(level_depth, _)| *level_depth == depth)
+        {
+            Some((_, level)) => level.push(write.clone()),
+            None => levels.push((depth, vec![write.clone()])),
+        }
+    }
+    levels.sort_by_key(|(depth, _)| *depth);
+    levels
+        .into_iter()
+        .map(|(_, level)| {
+            level
+                .chunks(BATCH_UPDATE_MAX_SIZE)
+                .map(|batch| batch.to_vec())
+                .collect()
+        })
+        .collect()
+}
+
+async fn commit_batch(
+    conn: FirestoreConnection,
+    batch: Vec<Write>,
+    database_path: String,
+    options: SeedOptions,
+    limiter: RampUpLimiter,
+) -> Result<SeedReport, BoxError> {
+    let mut report = SeedReport::default();
+    let batch = if options.precondition == SeedPrecondition::None {
+        batch
+    } else {
+        let (allowed, skipped) =
+            filter_by_precondition(conn.clone(), &batch, options.precondition).await?;
+        report.skipped.extend(skipped);
+        allowed
+    };
+    if batch.is_empty() {
+        return Ok(report);
+    }
+    limiter.acquire(batch.len()).await;
+
+    let committed = commit_isolating_rejections(conn, batch, &database_path, &options).await?;
+    report.written += committed.written;
+    report.skipped.extend(committed.skipped);
+    Ok(report)
+}
+
+/// Commits the batch, splitting it in halves when it is rejected because of a failed precondition
+/// until the rejected documents are found.
+fn commit_isolating_rejections<'a>(
+    conn: FirestoreConnection,
+    batch: Vec<Write>,
+    database_path: &'a str,
+    options: &'a SeedOptions,
+) -> BoxFuture<'a, Result<SeedReport, BoxError>> {
+    async move {
+        let mut report = SeedReport::default();
+        let error =
+            match commit_transaction(conn.clone(), batch.clone(), database_path, options).await {
+                Ok(()) => {
+                    report.written += batch.len();
+                    return Ok(report);
+                }
+                Err(error) => error,
+            };
+        let status = match error.downcast_ref::<Status>() {
+            Some(status) if is_precondition_failure(options.precondition, status) => status,
+            _ => return Err(error),
+        };
+        if let [write] = batch.as_slice() {
+            report.skipped.push(SkippedDocument {
+                path: write_document_name(write).to_string(),
+                reason: SkipReason::Rejected(status.message().to_string()),
+            });
+            return Ok(report);
+        }
+        let (first, second) = batch.split_at(batch.len() / 2);
+        for half in [first, second] {
+            let half_report =
+                commit_isolating_rejections(conn.clone(), half.to_vec(), database_path, options)
+                    .await?;
+            report.written += half_report.written;
+            report.skipped.extend(half_report.skipped);
+        }
+        Ok(report)
     }
-    Ok(operations.len())
+    .boxed()
 }
 
-fn generate_writes_for_collection<'a>(
-    collection: &'a CollectionData,
-    parent_path: &'a str,
-) -> Vec<Operation> {
+/// Splits the writes into the ones meeting the precondition and the skipped documents.
+async fn filter_by_precondition(
+    conn: FirestoreConnection,
+    writes: &[Write],
+    precondition: SeedPrecondition,
+) -> Result<(Vec<Write>, Vec<SkippedDocument>), BoxError> {
+    let names = writes
+        .iter()
+        .map(|write| write_document_name(write).to_string())
+        .collect();
+    let existing = get_documents(conn, names).await?;
+
+    let mut allowed = Vec::new();
+    let mut skipped = Vec::new();
+    for write in writes {
+        let path = write_document_name(write);
+        let exists = existing.contains_key(path);
+        match (precondition, exists) {
+            (SeedPrecondition::MustNotExist, true) => skipped.push(SkippedDocument {
+                path: path.to_string(),
+                reason: SkipReason::AlreadyExists,
+            }),
+            (SeedPrecondition::MustExist, false) => skipped.push(SkippedDocument {
+                path: path.to_string(),
+                reason: SkipReason::Missing,
+            }),
+            _ => allowed.push(write.clone()),
+        }
+    }
+    Ok((allowed, skipped))
+}
+
+fn is_precondition_failure(precondition: SeedPrecondition, status: &Status) -> bool {
+    precondition != SeedPrecondition::None
+        && matches!(
+            status.code(),
+            Code::FailedPrecondition | Code::AlreadyExists | Code::NotFound
+        )
+}
+
+pub(crate) fn write_document_name(write: &Write) -> &str {
+    match &write.operation {
+        Some(Operation::Update(document)) => &document.name,
+        Some(Operation::Delete(name)) => name,
+        Some(Operation::Transform(transform)) => &transform.document,
+        None => "",
+    }
+}
+
+/// Creates a write deleting the document with the given full name.
+pub(crate) fn delete_write(document_name: String) -> Write {
+    Write {
+        update_mask: None,
+        update_transforms: vec![],
+        current_document: None,
+        operation: Some(Operation::Delete(document_name)),
+    }
+}
+
+/// Lists full names of all documents of the collection and its subcollections.
+pub(crate) fn document_names_for_collection(
+    collection: &CollectionData,
+    parent_path: &str,
+) -> Vec<String> {
+    generate_writes_for_collection(collection, parent_path)
+        .iter()
+        .map(|write| write_document_name(write).to_string())
+        .collect()
+}
+
+/// Resolves a parent document path relative to the database into a full document name.
+///
+/// The root path resolves to `base_path` itself.
+pub(crate) fn parent_document_full_path(base_path: &str, parent_document_path: &str) -> String {
+    let trimmed_parent_path = parent_document_path.trim_matches('/');
+    if trimmed_parent_path.is_empty() {
+        base_path.to_string()
+    } else {
+        format!("{}/{}", base_path, trimmed_parent_path)
+    }
+}
+
+pub(crate) fn generate_writes_for_collection(
+    collection: &CollectionData,
+    parent_path: &str,
+) -> Vec<Write> {
+    generate_remapped_writes(collection, parent_path, &PathRemap::default())
+}
+
+/// Generates the writes of the collection with document names and references rewritten by the remapping rules.
+pub(crate) fn generate_remapped_writes(
+    collection: &CollectionData,
+    parent_path: &str,
+    remap: &PathRemap,
+) -> Vec<Write> {
     let collection_id = &collection.id;
     let documents = &collection.documents;
     let collection_path = format!("{}/{}", parent_path.trim_end_matches('/'), collection_id);
     documents
-        .into_iter()
-        .map(|document| generate_writes_for_document(document, &collection_path))
-        .flatten()
+        .iter()
+        .flat_map(|document| generate_writes_for_document(document, &collection_path, remap))
         .collect()
 }
 
 fn generate_writes_for_document(
     document: &super::type_mapping::DocumentData,
     collection_path: &str,
-) -> Vec<Operation> {
+    remap: &PathRemap,
+) -> Vec<Write> {
     let mut updates = Vec::new();
-    let firestore_doc = to_firestore_document(&document, &collection_path);
-    let document_path = firestore_doc.name.clone();
-    updates.push(Operation::Update(firestore_doc));
+    // subcollections are generated from the source path, so the rules are applied only once
+    let document_path = format!("{}/{}", collection_path.trim_end_matches('/'), document.id);
+    let (firestore_doc, transforms) = to_firestore_document(document, collection_path, remap);
+    updates.push(Write {
+        update_mask: None,
+        update_transforms: transforms,
+        current_document: None,
+        operation: Some(Operation::Update(firestore_doc)),
+    });
     if let Some(subcollections) = &document.subcollections {
         for subcollection in subcollections {
             let operations_for_subcollection =
-                generate_writes_for_collection(subcollection, &document_path);
+                generate_remapped_writes(subcollection, &document_path, remap);
             updates.extend(operations_for_subcollection);
         }
     }
     updates
 }
 
+/// Converts the document to its Firestore representation.
+///
+/// Sentinel values are not part of the document fields, they are returned as field transforms instead.
 fn to_firestore_document(
     document: &super::type_mapping::DocumentData,
     parent_path: &str,
-) -> firestore_grpc::v1::Document {
+    remap: &PathRemap,
+) -> (firestore_grpc::v1::Document, Vec<FieldTransform>) {
     let document_path = format!("{}/{}", parent_path.trim_end_matches('/'), document.id);
-    
-    firestore_grpc::v1::Document {
-        name: document_path,
-        fields: document
-            .data
-            .clone()
-            .into_iter()
-            .map(|(key, value)| {
-                (
-                    key,
-                    firestore_grpc::v1::Value {
-                        value_type: Some(to_firestore_value(value)),
-                    },
-                )
-            })
-            .collect::<HashMap<_, _>>(),
+    let mut transforms = Vec::new();
+    let fields = to_firestore_fields(&document.data, "", &mut transforms, remap);
+    transforms.sort_by(|a, b| a.field_path.cmp(&b.field_path));
+
+    let firestore_doc = firestore_grpc::v1::Document {
+        name: remap.apply_prefixes(&document_path),
+        fields,
         create_time: None,
         update_time: None,
+    };
+    (firestore_doc, transforms)
+}
+
+fn to_firestore_fields(
+    data: &HashMap<String, ValueType>,
+    prefix: &str,
+    transforms: &mut Vec<FieldTransform>,
+    remap: &PathRemap,
+) -> HashMap<String, firestore_grpc::v1::Value> {
+    let mut fields = HashMap::new();
+    for (key, value) in data {
+        let path = format!("{}{}", prefix, quote_field_path_segment(key));
+        if let Some(transform_type) = to_transform_type(value, remap) {
+            transforms.push(FieldTransform {
+                field_path: path,
+                transform_type: Some(transform_type),
+            });
+            continue;
+        }
+        let value_type = match value {
+            ValueType::MapValue(map) if !map.is_empty() => {
+                let map = map
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.as_ref().clone()))
+                    .collect();
+                let map_fields =
+                    to_firestore_fields(&map, &format!("{}.", path), transforms, remap);
+                if map_fields.is_empty() {
+                    // only sentinels inside, the transforms create the map
+                    continue;
+                }
+                firestore_grpc::v1::value::ValueType::MapValue(firestore_grpc::v1::MapValue {
+                    fields: map_fields,
+                })
+            }
+            _ => to_firestore_value_remapped(value.clone(), remap),
+        };
+        fields.insert(
+            key.clone(),
+            firestore_grpc::v1::Value {
+                value_type: Some(value_type),
+            },
+        );
     }
+    fields
 }
 
-async fn begin_transaction(
-    conn: FirestoreConnection,
-    database_path: &str,
-) -> Result<Vec<u8>, BoxError> {
-    let FirestoreConnection(mut client, _base_path) = conn;
-    let transaction_request = BeginTransactionRequest {
-        database: database_path.to_string(),
-        options: None,
+fn to_transform_type(value: &ValueType, remap: &PathRemap) -> Option<TransformType> {
+    let to_array = |values: &Vec<Box<ValueType>>| ArrayValue {
+        values: values
+            .iter()
+            .map(|value| firestore_grpc::v1::Value {
+                value_type: Some(to_firestore_value_remapped(value.as_ref().clone(), remap)),
+            })
+            .collect(),
     };
-    let transaction_response = client.begin_transaction(transaction_request).await?;
-    let transaction_response = transaction_response.into_inner();
-    Ok(transaction_response.transaction)
+    match value {
+        ValueType::ServerTimestamp => Some(TransformType::SetToServerValue(
+            ServerValue::RequestTime as i32,
+        )),
+        ValueType::Increment(by) => Some(TransformType::Increment(firestore_grpc::v1::Value {
+            value_type: Some(to_firestore_value_remapped(by.as_ref().clone(), remap)),
+        })),
+        ValueType::ArrayUnion(values) => {
+            Some(TransformType::AppendMissingElements(to_array(values)))
+        }
+        ValueType::ArrayRemove(values) => Some(TransformType::RemoveAllFromArray(to_array(values))),
+        _ => None,
+    }
 }
 
-async fn commit_transaction(
+/// Commits the writes atomically in a transaction of their own.
+///
+/// Beginning and committing the transaction is retried as a single unit, so that a transaction
+/// aborted by Firestore is replaced with a fresh one. Other transient failures are retried only
+/// when the writes carry neither transforms nor preconditions, as the failed commit might have
+/// been applied and repeating such writes would apply a transform twice or fail the precondition.
+pub(crate) async fn commit_transaction(
     conn: FirestoreConnection,
-    operations: Vec<Operation>,
-    transaction: Vec<u8>,
+    writes: Vec<Write>,
     database_path: &str,
+    options: &SeedOptions,
 ) -> Result<(), BoxError> {
-    let FirestoreConnection(mut client, _base_path) = conn;
-    
-    let commit_request = CommitRequest {
-        database: database_path.to_string(),
-        writes: operations
-            .into_iter()
-            .map(|operation| Write {
-                update_mask: None, // always override
-                update_transforms: vec![],
-                current_document: None,
-                operation: Some(operation),
-            })
-            .collect::<Vec<_>>(),
-        transaction,
-    };
-    let commit_response = client.commit(commit_request).await?;
-    let _commit_response = commit_response.into_inner();
+    let FirestoreConnection(client, _base_path, retrier) = conn;
+    let writes = writes
+        .into_iter()
+        .map(|write| apply_options(write, options))
+        .collect::<Vec<_>>();
+    let idempotent = writes.iter().all(is_idempotent);
+
+    retrier
+        .call_retrying(
+            |status| {
+                status.code() == Code::Aborted
+                    || (idempotent && retrier.policy().is_retriable(status))
+            },
+            || {
+                let mut client = client.clone();
+                let database = database_path.to_string();
+                let writes = writes.clone();
+                async move {
+                    let transaction = client
+                        .begin_transaction(BeginTransactionRequest {
+                            database: database.clone(),
+                            options: None,
+                        })
+                        .await?
+                        .into_inner()
+                        .transaction;
+                    client
+                        .commit(CommitRequest {
+                            database,
+                            writes,
+                            transaction,
+                        })
+                        .await
+                }
+            },
+        )
+        .await?;
     Ok(())
 }
 
-fn validate_document_path(path: &str) -> bool {
+/// Checks whether the write can be repeated without changing the outcome,
+/// a write with transforms or a precondition can't.
+pub(crate) fn is_idempotent(write: &Write) -> bool {
+    write.update_transforms.is_empty() && write.current_document.is_none()
+}
+
+/// Sets the update mask and the precondition required by the options.
+///
+/// Otherwise the write keeps its own mask and precondition, writes of a snapshot have none
+/// and override the whole document.
+pub(crate) fn apply_options(mut write: Write, options: &SeedOptions) -> Write {
+    if let Some(Operation::Update(document)) = &write.operation {
+        if options.merge {
+            write.update_mask = Some(update_mask_for_fields(&document.fields));
+        }
+        let exists = match options.precondition {
+            SeedPrecondition::None => None,
+            SeedPrecondition::MustNotExist => Some(false),
+            SeedPrecondition::MustExist => Some(true),
+        };
+        if let Some(exists) = exists {
+            write.current_document = Some(Precondition {
+                condition_type: Some(ConditionType::Exists(exists)),
+            });
+        }
+    }
+    write
+}
+
+/// Builds a mask covering every leaf field of the document.
+///
+/// Non-empty maps are descended into, so that merging `{"a": {"b": 1}}` keeps `a.c`
+/// of the existing document intact.
+fn update_mask_for_fields(fields: &HashMap<String, firestore_grpc::v1::Value>) -> DocumentMask {
+    let mut field_paths = Vec::new();
+    collect_field_paths(fields, "", &mut field_paths);
+    field_paths.sort();
+    DocumentMask { field_paths }
+}
+
+fn collect_field_paths(
+    fields: &HashMap<String, firestore_grpc::v1::Value>,
+    prefix: &str,
+    field_paths: &mut Vec<String>,
+) {
+    for (key, value) in fields {
+        let path = format!("{}{}", prefix, quote_field_path_segment(key));
+        match &value.value_type {
+            Some(firestore_grpc::v1::value::ValueType::MapValue(map)) if !map.fields.is_empty() => {
+                collect_field_paths(&map.fields, &format!("{}.", path), field_paths)
+            }
+            _ => field_paths.push(path),
+        }
+    }
+}
+
+/// Quotes a single segment of a field path with backticks unless it is a simple identifier.
+pub(crate) fn quote_field_path_segment(segment: &str) -> String {
+    let mut chars = segment.chars();
+    let is_simple = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if is_simple {
+        segment.to_string()
+    } else {
+        format!("`{}`", segment.replace('\\', "\\\\").replace('`', "\\`"))
+    }
+}
+
+pub(crate) fn validate_document_path(path: &str) -> bool {
     let trimmed = path.trim_matches('/');
     let parts = trimmed.split('/').filter(|t| !(*t).is_empty()).count();
     parts % 2 == 0
 }
 #[cfg(test)]
 mod tests {
-    use super::validate_document_path;
+    use super::*;
+
+    #[test]
+    fn test_ordered_batches() {
+        let documents = (0..501)
+            .map(|index| DocumentData {
+                id: index.to_string(),
+                data: HashMap::new(),
+                subcollections: Some(vec![CollectionData {
+                    id: "children".into(),
+                    documents: vec![DocumentData {
+                        id: "child".into(),
+                        data: HashMap::new(),
+                        subcollections: None,
+                    }],
+                }]),
+            })
+            .collect();
+        let collection = CollectionData {
+            id: "parents".into(),
+            documents,
+        };
+        let writes =
+            generate_writes_for_collection(&collection, "projects/p/databases/d/documents");
+
+        let levels = ordered_batches(&writes);
+        let sizes = levels
+            .iter()
+            .map(|level| level.iter().map(Vec::len).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![vec![500, 1], vec![500, 1]]);
+        assert!(levels[0]
+            .iter()
+            .flatten()
+            .all(|write| !write_document_name(write).contains("/children/")));
+    }
+
+    #[test]
+    fn test_path_remap() {
+        let remap = PathRemap {
+            project: Some("target".into()),
+            database: None,
+            prefixes: vec![("/users/".into(), "tenants/t1/users".into())],
+        };
+        assert_eq!(
+            remap.apply("projects/source/databases/(default)/documents/users/a/posts/1"),
+            "projects/target/databases/(default)/documents/tenants/t1/users/a/posts/1"
+        );
+        assert_eq!(
+            remap.apply("projects/source/databases/(default)/documents/usersettings/a"),
+            "projects/target/databases/(default)/documents/usersettings/a"
+        );
+        assert_eq!(remap.apply("not/a/document/path"), "not/a/document/path");
+        assert_eq!(
+            remap.apply_prefixes("projects/source/databases/(default)/documents/users/a"),
+            "projects/source/databases/(default)/documents/tenants/t1/users/a"
+        );
+    }
+
+    #[test]
+    fn test_remapped_writes() {
+        let remap = PathRemap {
+            project: Some("target".into()),
+            database: None,
+            prefixes: vec![("users".into(), "people".into())],
+        };
+        let mut data = HashMap::new();
+        data.insert(
+            "author".to_string(),
+            ValueType::ReferenceValue(
+                "projects/source/databases/(default)/documents/users/b".into(),
+            ),
+        );
+        let collection = CollectionData {
+            id: "users".into(),
+            documents: vec![DocumentData {
+                id: "a".into(),
+                data,
+                subcollections: Some(vec![CollectionData {
+                    id: "users".into(),
+                    documents: vec![DocumentData {
+                        id: "c".into(),
+                        data: HashMap::new(),
+                        subcollections: None,
+                    }],
+                }]),
+            }],
+        };
+
+        let writes = generate_remapped_writes(
+            &collection,
+            "projects/source/databases/(default)/documents",
+            &remap,
+        );
+
+        let names = writes.iter().map(write_document_name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "projects/source/databases/(default)/documents/people/a",
+                "projects/source/databases/(default)/documents/people/a/users/c",
+            ]
+        );
+        match &writes[0].operation {
+            Some(Operation::Update(document)) => assert_eq!(
+                document.fields["author"].value_type,
+                Some(firestore_grpc::v1::value::ValueType::ReferenceValue(
+                    "projects/target/databases/(default)/documents/people/b".into()
+                ))
+            ),
+            other => panic!("Unexpected operation {:?}", other),
+        }
+    }
 
     #[test]
     fn test_path_validation() {
@@ -194,4 +912,160 @@ mod tests {
         assert!(!validate_document_path("/coll/123/col/"));
         assert!(!validate_document_path("/coll/123/subcol/456/col/"));
     }
+
+    #[test]
+    fn test_field_path_quoting() {
+        assert_eq!(quote_field_path_segment("name"), "name");
+        assert_eq!(quote_field_path_segment("_private1"), "_private1");
+        assert_eq!(quote_field_path_segment("1st"), "`1st`");
+        assert_eq!(quote_field_path_segment("with space"), "`with space`");
+        assert_eq!(quote_field_path_segment("a.b"), "`a.b`");
+        assert_eq!(quote_field_path_segment("back`tick"), "`back\\`tick`");
+        assert_eq!(quote_field_path_segment(""), "``");
+    }
+
+    #[test]
+    fn test_update_mask_descends_into_maps() {
+        let mut nested = HashMap::new();
+        nested.insert(
+            "city".to_string(),
+            Box::new(ValueType::StringValue("Paris".into())),
+        );
+        nested.insert(
+            "zip code".to_string(),
+            Box::new(ValueType::IntegerValue(75001)),
+        );
+        let mut data = HashMap::new();
+        data.insert("name".to_string(), ValueType::StringValue("Jane".into()));
+        data.insert("address".to_string(), ValueType::MapValue(nested));
+        data.insert("tags".to_string(), ValueType::MapValue(HashMap::new()));
+        let document = DocumentData {
+            id: "jane".into(),
+            data,
+            subcollections: None,
+        };
+        let (firestore_doc, _) = to_firestore_document(
+            &document,
+            "projects/p/databases/d/documents/users",
+            &PathRemap::default(),
+        );
+
+        let mask = update_mask_for_fields(&firestore_doc.fields);
+
+        assert_eq!(
+            mask.field_paths,
+            vec!["address.`zip code`", "address.city", "name", "tags"]
+        );
+    }
+
+    #[test]
+    fn test_precondition_failure_detection() {
+        let already_exists = Status::new(Code::AlreadyExists, "exists");
+        let unavailable = Status::new(Code::Unavailable, "unavailable");
+        assert!(is_precondition_failure(
+            SeedPrecondition::MustNotExist,
+            &already_exists
+        ));
+        assert!(!is_precondition_failure(
+            SeedPrecondition::None,
+            &already_exists
+        ));
+        assert!(!is_precondition_failure(
+            SeedPrecondition::MustExist,
+            &unavailable
+        ));
+    }
+
+    #[test]
+    fn test_sentinels_become_field_transforms() {
+        let mut meta = HashMap::new();
+        meta.insert(
+            "updatedAt".to_string(),
+            Box::new(ValueType::ServerTimestamp),
+        );
+        let mut data = HashMap::new();
+        data.insert("name".to_string(), ValueType::StringValue("Jane".into()));
+        data.insert(
+            "visits".to_string(),
+            ValueType::Increment(Box::new(ValueType::IntegerValue(1))),
+        );
+        data.insert("meta".to_string(), ValueType::MapValue(meta));
+        let document = DocumentData {
+            id: "jane".into(),
+            data,
+            subcollections: None,
+        };
+
+        let (firestore_doc, transforms) = to_firestore_document(
+            &document,
+            "projects/p/databases/d/documents/users",
+            &PathRemap::default(),
+        );
+
+        assert_eq!(firestore_doc.fields.len(), 1);
+        assert!(firestore_doc.fields.contains_key("name"));
+        assert_eq!(
+            transforms,
+            vec![
+                FieldTransform {
+                    field_path: "meta.updatedAt".into(),
+                    transform_type: Some(TransformType::SetToServerValue(
+                        ServerValue::RequestTime as i32
+                    )),
+                },
+                FieldTransform {
+                    field_path: "visits".into(),
+                    transform_type: Some(TransformType::Increment(firestore_grpc::v1::Value {
+                        value_type: Some(firestore_grpc::v1::value::ValueType::IntegerValue(1)),
+                    })),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timestamp_serialization() {
+        let whole = ValueType::TimestampValue(Timestamp::from(1_600_000_000));
+        let precise = ValueType::TimestampValue(Timestamp {
+            seconds: 1_600_000_000,
+            nanos: 123_456_000,
+        });
+        let whole_json = serde_json::to_string(&whole).unwrap();
+        let precise_json = serde_json::to_string(&precise).unwrap();
+
+        assert_eq!(whole_json, r#"{"TimestampValue":1600000000}"#);
+        assert_eq!(precise_json, r#"{"TimestampValue":[1600000000,123456000]}"#);
+        assert_eq!(
+            serde_json::from_str::<ValueType>(&whole_json).unwrap(),
+            whole
+        );
+        assert_eq!(
+            serde_json::from_str::<ValueType>(&precise_json).unwrap(),
+            precise
+        );
+        assert_eq!(
+            from_firestore_value(to_firestore_value(precise.clone())),
+            precise
+        );
+    }
+
+    #[test]
+    fn test_has_skipped_parent() {
+        let base = "projects/p/databases/d/documents";
+        let skipped = vec![format!("{}/users/a", base)].into_iter().collect();
+
+        assert!(has_skipped_parent(
+            &format!("{}/users/a/posts/1", base),
+            &skipped
+        ));
+        assert!(has_skipped_parent(
+            &format!("{}/users/a/posts/1/comments/2", base),
+            &skipped
+        ));
+        assert!(!has_skipped_parent(&format!("{}/users/a", base), &skipped));
+        assert!(!has_skipped_parent(
+            &format!("{}/users/ab/posts/1", base),
+            &skipped
+        ));
+    }
 }
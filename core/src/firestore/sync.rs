@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+
+use firestore_grpc::v1::Write;
+use serde::Serialize;
+
+use super::{
+    collect::collect_collection,
+    diff::{diff_collections, CollectionDiff},
+    seed::{
+        commit_writes, delete_write, generate_writes_for_collection, parent_document_full_path,
+        validate_document_path, write_document_name, CollectionData, SeedError, SeedOptions,
+    },
+    FirestoreConnection,
+};
+
+/// Write counts of a synchronization.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    pub created: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+    /// Number of Firestore calls retried after a transient error.
+    pub retries: usize,
+}
+
+/// Makes the collection in Firestore mirror the snapshot with the minimal number of writes.
+///
+/// The live collection is collected and compared with the snapshot, then only new and
+/// changed documents are written and documents absent from the snapshot are deleted.
+/// The writes follow the precondition, concurrency and undo log of the options,
+/// the options a synchronization can't honour are rejected, see [`validate_sync_options`].
+pub async fn sync_collection(
+    conn: FirestoreConnection,
+    snapshot: &CollectionData,
+    parent_document_path: &str,
+    options: &SeedOptions,
+) -> Result<SyncReport, SeedError> {
+    if !validate_document_path(parent_document_path) {
+        return Err(SeedError::InvalidPath);
+    }
+    validate_sync_options(options)?;
+    let conn = conn.scoped_retries();
+    let parent_path = parent_document_full_path(&conn.1, parent_document_path);
+    let live = collect_collection(conn.clone(), format!("{}/{}", parent_path, snapshot.id))
+        .await
+        .map_err(SeedError::FirestoreClientError)?;
+    let diff = diff_collections(&live, snapshot);
+
+    let all_writes = generate_writes_for_collection(snapshot, &parent_path);
+    let total = all_writes.len();
+    let writes = sync_writes(&diff, all_writes, &parent_path);
+    let mut report = SyncReport {
+        created: diff.added.len(),
+        updated: diff.changed.len(),
+        deleted: diff.removed.len(),
+        unchanged: total - diff.added.len() - diff.changed.len(),
+        retries: 0,
+    };
+    if !writes.is_empty() {
+        let database_path = conn.1.trim_end_matches("/documents").to_string();
+        commit_writes(conn.clone(), &writes, &database_path, options)
+            .await
+            .map_err(SeedError::FirestoreClientError)?;
+    }
+    report.retries = conn.2.retries();
+    Ok(report)
+}
+
+/// Rejects the options a synchronization can't honour.
+///
+/// The snapshot is compared with the collection at its own path, so it can't be remapped
+/// or given new IDs. A journal is not needed, an interrupted synchronization is resumed
+/// by running it again, which only writes what is still different.
+pub fn validate_sync_options(options: &SeedOptions) -> Result<(), SeedError> {
+    let unsupported = if !options.remap.is_empty() {
+        Some("remapped paths")
+    } else if options.regenerate_ids {
+        Some("regenerated IDs")
+    } else if options.journal.is_some() || options.resume {
+        Some("a journal")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(option) => Err(SeedError::FirestoreClientError(
+            format!("A synchronization can't be run with {}", option).into(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Picks the writes of added and changed documents and adds deletes of removed ones.
+fn sync_writes(diff: &CollectionDiff, all_writes: Vec<Write>, parent_path: &str) -> Vec<Write> {
+    let to_write = diff
+        .added
+        .iter()
+        .chain(diff.changed.iter().map(|document| &document.path))
+        .map(|path| format!("{}/{}", parent_path, path))
+        .collect::<HashSet<_>>();
+
+    let mut writes = all_writes
+        .into_iter()
+        .filter(|write| to_write.contains(write_document_name(write)))
+        .collect::<Vec<_>>();
+    writes.extend(
+        diff.removed
+            .iter()
+            .map(|path| delete_write(format!("{}/{}", parent_path, path))),
+    );
+    writes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::seed::{DocumentData, PathRemap, SeedPrecondition, ValueType};
+    use firestore_grpc::v1::write::Operation;
+    use std::collections::HashMap;
+
+    fn collection(documents: Vec<(&str, i64)>) -> CollectionData {
+        CollectionData {
+            id: "counters".into(),
+            documents: documents
+                .into_iter()
+                .map(|(id, count)| DocumentData {
+                    id: id.to_string(),
+                    data: vec![("count".to_string(), ValueType::IntegerValue(count))]
+                        .into_iter()
+                        .collect::<HashMap<_, _>>(),
+                    subcollections: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_sync_writes() {
+        let parent_path = "projects/p/databases/(default)/documents";
+        let live = collection(vec![("a", 1), ("b", 2), ("c", 3)]);
+        let snapshot = collection(vec![("a", 1), ("b", 5), ("d", 4)]);
+        let diff = diff_collections(&live, &snapshot);
+
+        let writes = sync_writes(
+            &diff,
+            generate_writes_for_collection(&snapshot, parent_path),
+            parent_path,
+        );
+
+        let names = writes
+            .iter()
+            .map(|write| match &write.operation {
+                Some(Operation::Update(document)) => format!("update {}", document.name),
+                Some(Operation::Delete(name)) => format!("delete {}", name),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                format!("update {}/counters/b", parent_path),
+                format!("update {}/counters/d", parent_path),
+                format!("delete {}/counters/c", parent_path),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_sync_options() {
+        let supported = SeedOptions {
+            precondition: SeedPrecondition::MustExist,
+            concurrency: 4,
+            undo_log: Some("undo.json".into()),
+            ..SeedOptions::default()
+        };
+        assert!(validate_sync_options(&supported).is_ok());
+
+        let unsupported = vec![
+            SeedOptions {
+                remap: PathRemap {
+                    project: Some("other".into()),
+                    ..PathRemap::default()
+                },
+                ..SeedOptions::default()
+            },
+            SeedOptions {
+                regenerate_ids: true,
+                ..SeedOptions::default()
+            },
+            SeedOptions {
+                journal: Some("journal.json".into()),
+                ..SeedOptions::default()
+            },
+        ];
+        for options in unsupported {
+            assert!(validate_sync_options(&options).is_err());
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::{sleep_until, Instant};
+
+/// Number of operations per second allowed at the start of a seed.
+const RAMP_UP_BASE_RATE: f64 = 500.0;
+/// Growth of the allowed rate after every ramp-up period.
+const RAMP_UP_GROWTH: f64 = 1.5;
+/// Length of a ramp-up period in seconds.
+const RAMP_UP_PERIOD: u64 = 5 * 60;
+
+/// Limits the rate of writes following the 500/50/5 rule recommended by Firestore:
+/// start with 500 operations per second and increase the traffic by 50% every 5 minutes.
+///
+/// Clones share the same budget.
+#[derive(Clone)]
+pub(crate) struct RampUpLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+struct LimiterState {
+    started: Instant,
+    next_slot: Instant,
+}
+
+impl RampUpLimiter {
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        RampUpLimiter {
+            state: Arc::new(Mutex::new(LimiterState {
+                started: now,
+                next_slot: now,
+            })),
+        }
+    }
+
+    /// Waits until the given number of operations can be sent.
+    pub(crate) async fn acquire(&self, operations: usize) {
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let rate = ramp_up_rate(now - state.started);
+            let slot = state.next_slot.max(now);
+            state.next_slot = slot + Duration::from_secs_f64(operations as f64 / rate);
+            slot
+        };
+        sleep_until(slot).await;
+    }
+}
+
+/// Operations per second allowed after the given time since the start.
+fn ramp_up_rate(elapsed: Duration) -> f64 {
+    let periods = (elapsed.as_secs() / RAMP_UP_PERIOD) as i32;
+    RAMP_UP_BASE_RATE * RAMP_UP_GROWTH.powi(periods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ramp_up_rate() {
+        assert_eq!(ramp_up_rate(Duration::from_secs(0)), 500.0);
+        assert_eq!(ramp_up_rate(Duration::from_secs(299)), 500.0);
+        assert_eq!(ramp_up_rate(Duration::from_secs(300)), 750.0);
+        assert_eq!(ramp_up_rate(Duration::from_secs(600)), 1125.0);
+    }
+}
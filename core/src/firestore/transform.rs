@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    collect::{collect_collection_resumable_with_transformer, collect_collection_with_transformer},
+    migrate::{apply_operation, get_field, split_field_path, MigrationOperation},
+    seed::{
+        seed_collection_with_options, CollectionData, DocumentData, SeedError, SeedOptions,
+        SeedReport, ValueType,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// Custom logic run on every document flowing through an export or a seed.
+///
+/// The transformer receives the document together with its path relative to the database and
+/// returns the documents taking its place: none to drop it, the modified document, or any number
+/// of replacements. Subcollections of the returned documents are transformed afterwards.
+pub trait DocumentTransformer: Send + Sync {
+    fn transform(&self, path: &str, document: DocumentData) -> Result<Vec<DocumentData>, BoxError>;
+}
+
+impl<F> DocumentTransformer for F
+where
+    F: Fn(&str, DocumentData) -> Result<Vec<DocumentData>, BoxError> + Send + Sync,
+{
+    fn transform(&self, path: &str, document: DocumentData) -> Result<Vec<DocumentData>, BoxError> {
+        self(path, document)
+    }
+}
+
+/// Runs transformers one after another, every document returned by a transformer is passed to the next one.
+#[derive(Default)]
+pub struct TransformerChain {
+    transformers: Vec<Box<dyn DocumentTransformer>>,
+}
+
+impl TransformerChain {
+    pub fn new(transformers: Vec<Box<dyn DocumentTransformer>>) -> Self {
+        TransformerChain { transformers }
+    }
+
+    pub fn push(&mut self, transformer: Box<dyn DocumentTransformer>) {
+        self.transformers.push(transformer);
+    }
+}
+
+impl DocumentTransformer for TransformerChain {
+    fn transform(&self, path: &str, document: DocumentData) -> Result<Vec<DocumentData>, BoxError> {
+        let collection_path = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        let mut documents = vec![document];
+        for transformer in &self.transformers {
+            let mut transformed = Vec::new();
+            for document in documents {
+                let path = document_path(collection_path, &document.id);
+                transformed.extend(transformer.transform(&path, document)?);
+            }
+            documents = transformed;
+        }
+        Ok(documents)
+    }
+}
+
+/// Built-in transform described in JSON, e.g. in a file passed to the CLI.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeclarativeTransform {
+    /// Changes fields of every document, see [`MigrationOperation`].
+    Field(MigrationOperation),
+    /// Keeps only the documents having all of the values. Keys are field paths with segments separated by dots.
+    KeepWhere {
+        keep_where: HashMap<String, ValueType>,
+    },
+    /// Drops the documents having all of the values. Keys are field paths with segments separated by dots.
+    DropWhere {
+        drop_where: HashMap<String, ValueType>,
+    },
+}
+
+impl DocumentTransformer for DeclarativeTransform {
+    fn transform(
+        &self,
+        _path: &str,
+        document: DocumentData,
+    ) -> Result<Vec<DocumentData>, BoxError> {
+        let DocumentData {
+            id,
+            data,
+            subcollections,
+        } = document;
+        let mut value = ValueType::MapValue(
+            data.into_iter()
+                .map(|(key, value)| (key, Box::new(value)))
+                .collect(),
+        );
+        let keep = match self {
+            DeclarativeTransform::Field(operation) => {
+                apply_operation(&mut value, operation, &mut Vec::new())
+                    .map_err(|reason| format!("Could not transform {}: {}", id, reason))?;
+                true
+            }
+            DeclarativeTransform::KeepWhere { keep_where } => matches_all(&value, keep_where),
+            DeclarativeTransform::DropWhere { drop_where } => !matches_all(&value, drop_where),
+        };
+        if !keep {
+            return Ok(vec![]);
+        }
+        let data = match value {
+            ValueType::MapValue(map) => map.into_iter().map(|(key, value)| (key, *value)).collect(),
+            _ => HashMap::new(),
+        };
+        Ok(vec![DocumentData {
+            id,
+            data,
+            subcollections,
+        }])
+    }
+}
+
+/// Transforms every document of the collection and of its subcollections.
+///
+/// `parent_path` is the path of the parent document relative to the database, `/` for the root.
+pub fn transform_collection(
+    collection: CollectionData,
+    parent_path: &str,
+    transformer: &dyn DocumentTransformer,
+) -> Result<CollectionData, BoxError> {
+    let collection_path = document_path(parent_path, &collection.id);
+    let mut documents = Vec::new();
+    for document in collection.documents {
+        let path = document_path(&collection_path, &document.id);
+        for mut document in transformer.transform(&path, document)? {
+            let path = document_path(&collection_path, &document.id);
+            document.subcollections = document
+                .subcollections
+                .map(|subcollections| {
+                    subcollections
+                        .into_iter()
+                        .map(|subcollection| {
+                            transform_collection(subcollection, &path, transformer)
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?;
+            documents.push(document);
+        }
+    }
+    Ok(CollectionData {
+        id: collection.id,
+        documents,
+    })
+}
+
+/// Collects the collection like [`collect_collection`](super::collect::collect_collection),
+/// passing every document through the transformer as soon as it is listed.
+///
+/// The transformer receives the documents without their subcollections. Subcollections are only
+/// collected for the documents it returns, and are attached to each of them.
+pub async fn collect_collection_transformed(
+    conn: FirestoreConnection,
+    full_path: String,
+    transformer: &dyn DocumentTransformer,
+) -> Result<CollectionData, BoxError> {
+    collect_collection_with_transformer(conn, full_path, transformer).await
+}
+
+/// Collects the collection like [`collect_collection_transformed`], saving the progress to
+/// the checkpoint file like [`collect_collection_resumable`](super::collect::collect_collection_resumable).
+///
+/// The checkpoint records the transformed documents, so fields dropped or scrubbed by the
+/// transformer are never written to disk. An interrupted export has to be resumed
+/// with the same transformer.
+pub async fn collect_collection_resumable_transformed(
+    conn: FirestoreConnection,
+    full_path: String,
+    checkpoint_path: &str,
+    transformer: &dyn DocumentTransformer,
+) -> Result<CollectionData, BoxError> {
+    collect_collection_resumable_with_transformer(conn, full_path, checkpoint_path, transformer)
+        .await
+}
+
+/// Seeds the collection like [`seed_collection_with_options`], passing every document through the transformer first.
+pub async fn seed_collection_transformed(
+    conn: FirestoreConnection,
+    collection: &CollectionData,
+    parent_document_path: &str,
+    options: &SeedOptions,
+    transformer: &dyn DocumentTransformer,
+) -> Result<SeedReport, SeedError> {
+    let collection = transform_collection(collection.clone(), parent_document_path, transformer)
+        .map_err(SeedError::FirestoreClientError)?;
+    seed_collection_with_options(conn, &collection, parent_document_path, options).await
+}
+
+fn document_path(parent_path: &str, id: &str) -> String {
+    let parent_path = parent_path.trim_matches('/');
+    if parent_path.is_empty() {
+        id.to_string()
+    } else {
+        format!("{}/{}", parent_path, id)
+    }
+}
+
+fn matches_all(value: &ValueType, filter: &HashMap<String, ValueType>) -> bool {
+    filter
+        .iter()
+        .all(|(path, expected)| get_field(value, &split_field_path(path)) == Some(expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn document(id: &str, fields: Vec<(&str, ValueType)>) -> DocumentData {
+        DocumentData {
+            id: id.to_string(),
+            data: fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            subcollections: None,
+        }
+    }
+
+    #[test]
+    fn test_transform_collection() {
+        let mut user = document(
+            "u1",
+            vec![("email", ValueType::StringValue("a@b.c".into()))],
+        );
+        user.subcollections = Some(vec![CollectionData {
+            id: "orders".into(),
+            documents: vec![
+                document("o1", vec![("test", ValueType::BooleanValue(true))]),
+                document("o2", vec![]),
+            ],
+        }]);
+        let collection = CollectionData {
+            id: "users".into(),
+            documents: vec![user],
+        };
+        let transforms: Vec<DeclarativeTransform> = serde_json::from_str(
+            r#"[
+                {"op": "delete", "field": "email"},
+                {"drop_where": {"test": {"BooleanValue": true}}}
+            ]"#,
+        )
+        .unwrap();
+        let mut chain = TransformerChain::new(
+            transforms
+                .into_iter()
+                .map(|transform| Box::new(transform) as Box<dyn DocumentTransformer>)
+                .collect(),
+        );
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let seen_paths = paths.clone();
+        chain.push(Box::new(move |path: &str, document: DocumentData| {
+            seen_paths.lock().unwrap().push(path.to_string());
+            Ok(vec![document])
+        }));
+
+        let transformed = transform_collection(collection, "/tenants/t1", &chain).unwrap();
+
+        assert_eq!(transformed.documents[0].data, HashMap::new());
+        let orders = &transformed.documents[0].subcollections.as_ref().unwrap()[0];
+        assert_eq!(orders.documents, vec![document("o2", vec![])]);
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec!["tenants/t1/users/u1", "tenants/t1/users/u1/orders/o2"]
+        );
+    }
+}
@@ -1,32 +1,81 @@
 use std::collections::HashMap;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use firestore_grpc::google::r#type::LatLng;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ValueType {
     NullValue,
     BooleanValue(bool),
     IntegerValue(i64),
     DoubleValue(f64),
-    TimestampValue(i64),
+    TimestampValue(Timestamp),
     StringValue(String),
     BytesValue(Vec<u8>),
     ReferenceValue(String),
     GeoPointValue((f64, f64)),
     ArrayValue(Vec<Box<ValueType>>),
     MapValue(HashMap<String, Box<ValueType>>),
+    /// Sentinel set to the time at which the server processes the write.
+    ServerTimestamp,
+    /// Sentinel adding the value to the current numeric value of the field.
+    Increment(Box<ValueType>),
+    /// Sentinel appending the elements which are not yet present in the current array value of the field.
+    ArrayUnion(Vec<Box<ValueType>>),
+    /// Sentinel removing the elements from the current array value of the field.
+    ArrayRemove(Vec<Box<ValueType>>),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Point in time with nanosecond precision.
+///
+/// Serialized as the number of seconds when there is no fraction of a second,
+/// otherwise as a pair of seconds and nanoseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanos: i32,
+}
+
+impl From<i64> for Timestamp {
+    fn from(seconds: i64) -> Self {
+        Timestamp { seconds, nanos: 0 }
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.nanos == 0 {
+            self.seconds.serialize(serializer)
+        } else {
+            (self.seconds, self.nanos).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Seconds(i64),
+            Precise((i64, i32)),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Seconds(seconds) => Timestamp::from(seconds),
+            Repr::Precise((seconds, nanos)) => Timestamp { seconds, nanos },
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct DocumentData {
     pub id: String,
     pub data: HashMap<String, ValueType>,
     pub subcollections: Option<Vec<CollectionData>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CollectionData {
     pub id: String,
     pub documents: Vec<DocumentData>,
@@ -39,7 +88,10 @@ pub fn from_firestore_value(raw_value: firestore_grpc::v1::value::ValueType) ->
         firestore_grpc::v1::value::ValueType::IntegerValue(i) => ValueType::IntegerValue(i),
         firestore_grpc::v1::value::ValueType::DoubleValue(d) => ValueType::DoubleValue(d),
         firestore_grpc::v1::value::ValueType::TimestampValue(t) => {
-            ValueType::TimestampValue(t.seconds.into())
+            ValueType::TimestampValue(Timestamp {
+                seconds: t.seconds,
+                nanos: t.nanos,
+            })
         }
         firestore_grpc::v1::value::ValueType::StringValue(s) => ValueType::StringValue(s),
         firestore_grpc::v1::value::ValueType::BytesValue(val) => ValueType::BytesValue(val),
@@ -52,27 +104,115 @@ pub fn from_firestore_value(raw_value: firestore_grpc::v1::value::ValueType) ->
         firestore_grpc::v1::value::ValueType::ArrayValue(av) => ValueType::ArrayValue(
             av.values
                 .into_iter()
-                .map(|val| {
+                .filter_map(|val| {
                     val.value_type
                         .map(|inner_val| Box::new(from_firestore_value(inner_val)))
                 })
-                .filter(|option| option.is_some())
-                .map(|option| option.unwrap())
                 .collect::<Vec<Box<ValueType>>>(),
         ),
         firestore_grpc::v1::value::ValueType::MapValue(mv) => ValueType::MapValue(
             mv.fields
                 .into_iter()
-                .filter_map(|(key, value)| match value.value_type {
-                    Some(inner_value) => Some((key, Box::new(from_firestore_value(inner_value)))),
-                    None => None,
+                .filter_map(|(key, value)| {
+                    value
+                        .value_type
+                        .map(|inner_value| (key, Box::new(from_firestore_value(inner_value))))
                 })
                 .collect::<HashMap<String, Box<ValueType>>>(),
         ),
     }
 }
 
+/// Rules rewriting document paths of a snapshot seeded into another project, database or parent.
+///
+/// Prefixes are applied to the names of the seeded documents and to reference values.
+/// The project and the database are only replaced in reference values, the documents themselves
+/// are written into the database of the connection.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PathRemap {
+    /// Project id replacing the project of the paths.
+    pub project: Option<String>,
+    /// Database id replacing the database of the paths.
+    pub database: Option<String>,
+    /// Pairs of path prefixes relative to the database root, the first matching prefix is replaced by its pair.
+    pub prefixes: Vec<(String, String)>,
+}
+
+impl PathRemap {
+    pub fn is_empty(&self) -> bool {
+        self.project.is_none() && self.database.is_none() && self.prefixes.is_empty()
+    }
+
+    /// Rewrites the full document path (`projects/{project}/databases/{database}/documents/...`).
+    ///
+    /// Paths in another format are returned unchanged.
+    pub fn apply(&self, path: &str) -> String {
+        self.rewrite(path, true)
+    }
+
+    /// Rewrites only the prefix of the full document path, keeping its project and database.
+    pub fn apply_prefixes(&self, path: &str) -> String {
+        self.rewrite(path, false)
+    }
+
+    fn rewrite(&self, path: &str, replace_database: bool) -> String {
+        let segments = path.splitn(6, '/').collect::<Vec<_>>();
+        let (project, database, relative) = match segments.as_slice() {
+            ["projects", project, "databases", database, "documents"] => (*project, *database, ""),
+            ["projects", project, "databases", database, "documents", relative] => {
+                (*project, *database, *relative)
+            }
+            _ => return path.to_string(),
+        };
+        let relative = self
+            .prefixes
+            .iter()
+            .map(|(from, to)| (from.trim_matches('/'), to.trim_matches('/')))
+            .find_map(|(from, to)| {
+                if relative == from {
+                    Some(to.to_string())
+                } else {
+                    relative
+                        .strip_prefix(from)
+                        .filter(|rest| rest.starts_with('/'))
+                        .map(|rest| {
+                            format!("{}{}", to, rest)
+                                .trim_start_matches('/')
+                                .to_string()
+                        })
+                }
+            })
+            .unwrap_or_else(|| relative.to_string());
+        let (project, database) = if replace_database {
+            (
+                self.project.as_deref().unwrap_or(project),
+                self.database.as_deref().unwrap_or(database),
+            )
+        } else {
+            (project, database)
+        };
+        let base = format!("projects/{}/databases/{}/documents", project, database);
+        if relative.is_empty() {
+            base
+        } else {
+            format!("{}/{}", base, relative)
+        }
+    }
+}
+
+/// Converts the value to its Firestore representation.
+///
+/// Sentinels are turned into field transforms by the seeder. They have no representation
+/// as a plain value, so where a transform is not possible (e.g. inside of an array) they become `null`.
 pub fn to_firestore_value(value: ValueType) -> firestore_grpc::v1::value::ValueType {
+    to_firestore_value_remapped(value, &PathRemap::default())
+}
+
+/// Converts the value to its Firestore representation rewriting references with the remapping rules.
+pub fn to_firestore_value_remapped(
+    value: ValueType,
+    remap: &PathRemap,
+) -> firestore_grpc::v1::value::ValueType {
     match value {
         ValueType::NullValue => firestore_grpc::v1::value::ValueType::NullValue(0),
         ValueType::BooleanValue(val) => firestore_grpc::v1::value::ValueType::BooleanValue(val),
@@ -80,34 +220,50 @@ pub fn to_firestore_value(value: ValueType) -> firestore_grpc::v1::value::ValueT
         ValueType::DoubleValue(val) => firestore_grpc::v1::value::ValueType::DoubleValue(val),
         ValueType::TimestampValue(val) => {
             let timestamp = prost_types::Timestamp {
-                seconds: val,
-                nanos: 0,
+                seconds: val.seconds,
+                nanos: val.nanos,
             };
             firestore_grpc::v1::value::ValueType::TimestampValue(timestamp)
         }
         ValueType::StringValue(val) => firestore_grpc::v1::value::ValueType::StringValue(val),
         ValueType::BytesValue(val) => firestore_grpc::v1::value::ValueType::BytesValue(val),
-        ValueType::ReferenceValue(val) => firestore_grpc::v1::value::ValueType::ReferenceValue(val),
+        ValueType::ReferenceValue(val) => {
+            firestore_grpc::v1::value::ValueType::ReferenceValue(remap.apply(&val))
+        }
         ValueType::GeoPointValue((lat, long)) => {
             firestore_grpc::v1::value::ValueType::GeoPointValue(LatLng {
                 latitude: lat,
                 longitude: long,
             })
         }
-        ValueType::ArrayValue(val) => 
-            firestore_grpc::v1::value::ValueType::ArrayValue(
-                firestore_grpc::v1::ArrayValue{ values: val.into_iter().map(|item| {
-                let value: ValueType = *item;
-                firestore_grpc::google::firestore::v1::Value{value_type: Some(to_firestore_value(value))}
-            }).collect::<Vec<_>>()}),
-        
+        ValueType::ArrayValue(val) => {
+            firestore_grpc::v1::value::ValueType::ArrayValue(firestore_grpc::v1::ArrayValue {
+                values: val
+                    .into_iter()
+                    .map(|item| firestore_grpc::google::firestore::v1::Value {
+                        value_type: Some(to_firestore_value_remapped(*item, remap)),
+                    })
+                    .collect::<Vec<_>>(),
+            })
+        }
         ValueType::MapValue(val) => firestore_grpc::v1::value::ValueType::MapValue(
             firestore_grpc::google::firestore::v1::MapValue {
-                fields: val.into_iter().map(|(key, value)| {
-                    let inner_val = *value;
-                    (key, firestore_grpc::google::firestore::v1::Value{value_type: Some(to_firestore_value(inner_val))})
-                }).collect::<HashMap<_,_>>()
-            }
+                fields: val
+                    .into_iter()
+                    .map(|(key, value)| {
+                        (
+                            key,
+                            firestore_grpc::google::firestore::v1::Value {
+                                value_type: Some(to_firestore_value_remapped(*value, remap)),
+                            },
+                        )
+                    })
+                    .collect::<HashMap<_, _>>(),
+            },
         ),
+        ValueType::ServerTimestamp
+        | ValueType::Increment(_)
+        | ValueType::ArrayUnion(_)
+        | ValueType::ArrayRemove(_) => firestore_grpc::v1::value::ValueType::NullValue(0),
     }
 }
@@ -0,0 +1,395 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::Serialize;
+
+use super::{
+    collect::collect_collection,
+    hash::fnv1a,
+    migrate::{migrate_collection, Migration, MigrationProgress, MigrationReport},
+    seed::{
+        seed_collection_with_options, CollectionData, DocumentData, SeedOptions, SeedPrecondition,
+        ValueType,
+    },
+    BoxError, FirestoreConnection,
+};
+
+/// A migration file named `<version>_<name>.json`, e.g. `0003_rename_email.json`.
+#[derive(Debug, Clone)]
+pub struct VersionedMigration {
+    pub version: u64,
+    /// File name without the extension, used as the ID of its record in the tracking collection.
+    pub id: String,
+    /// Checksum of the file content, detecting files changed after they have been applied.
+    pub checksum: String,
+    pub migration: Migration,
+}
+
+/// Result of applying the pending migrations.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MigrateUpReport {
+    /// Set when nothing has been written and the report is only a preview.
+    pub dry_run: bool,
+    /// Number of migrations applied by earlier runs.
+    pub already_applied: usize,
+    /// Migrations applied by this run, in order.
+    pub applied: Vec<AppliedMigration>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedMigration {
+    pub id: String,
+    pub report: MigrationReport,
+}
+
+/// Reads the migration files of the directory ordered by their versions.
+///
+/// Files without the `.json` extension are ignored.
+pub fn load_migrations(directory: &str) -> Result<Vec<VersionedMigration>, BoxError> {
+    let mut migrations = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default()
+            .to_string();
+        let version = parse_version(&id).ok_or_else(|| {
+            format!(
+                "Invalid migration file name {}, expected `<version>_<name>.json`",
+                path.display()
+            )
+        })?;
+        migrations.push(read_migration(&path, version, id)?);
+    }
+    migrations.sort_by_key(|migration| migration.version);
+    if let Some(pair) = migrations
+        .windows(2)
+        .find(|pair| pair[0].version == pair[1].version)
+    {
+        return Err(format!(
+            "Migrations {} and {} have the same version",
+            pair[0].id, pair[1].id
+        )
+        .into());
+    }
+    Ok(migrations)
+}
+
+/// Applies the migrations which are not recorded in the tracking collection yet, in order.
+///
+/// Every applied migration is recorded as a document of the tracking collection, so that the
+/// next run continues with the following one. The run fails before writing anything when an
+/// applied migration file has been changed, or when a pending migration is older than the latest
+/// applied one. A migration failing for some documents is not recorded and stops the run.
+///
+/// With `dry_run` set the pending migrations are previewed one by one, without taking changes of
+/// the previous pending migrations into account.
+pub async fn migrate_up<F>(
+    conn: FirestoreConnection,
+    migrations: &[VersionedMigration],
+    tracking_collection: &str,
+    dry_run: bool,
+    on_progress: F,
+) -> Result<MigrateUpReport, BoxError>
+where
+    F: Fn(&VersionedMigration, MigrationProgress) + Send + Sync,
+{
+    let tracking_collection = tracking_collection.trim_matches('/');
+    let applied = applied_migrations(conn.clone(), tracking_collection).await?;
+    check_applied_migrations(migrations, &applied)?;
+
+    let mut report = MigrateUpReport {
+        dry_run,
+        already_applied: applied.len(),
+        ..MigrateUpReport::default()
+    };
+    for migration in migrations
+        .iter()
+        .filter(|migration| !applied.contains_key(&migration.id))
+    {
+        let migration_report =
+            migrate_collection(conn.clone(), &migration.migration, dry_run, |progress| {
+                on_progress(migration, progress)
+            })
+            .await?;
+        if !dry_run {
+            if !migration_report.failed.is_empty() {
+                return Err(format!(
+                    "Migration {} failed for {} documents and is not recorded as applied",
+                    migration.id,
+                    migration_report.failed.len()
+                )
+                .into());
+            }
+            record_migration(
+                conn.clone(),
+                tracking_collection,
+                migration,
+                &migration_report,
+            )
+            .await?;
+        }
+        report.applied.push(AppliedMigration {
+            id: migration.id.clone(),
+            report: migration_report,
+        });
+    }
+    Ok(report)
+}
+
+/// Fails when an applied migration file has been changed, or when a pending migration is older
+/// than the latest applied one.
+fn check_applied_migrations(
+    migrations: &[VersionedMigration],
+    applied: &HashMap<String, (u64, String)>,
+) -> Result<(), BoxError> {
+    let latest_applied = applied.values().map(|(version, _)| *version).max();
+    for migration in migrations {
+        match applied.get(&migration.id) {
+            Some((_, checksum)) if *checksum != migration.checksum => {
+                return Err(format!(
+                    "Migration {} has been changed after it was applied",
+                    migration.id
+                )
+                .into())
+            }
+            None if latest_applied.is_some_and(|latest| migration.version < latest) => {
+                return Err(format!(
+                    "Migration {} is older than the latest applied migration",
+                    migration.id
+                )
+                .into())
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reads the version and the checksum of the applied migrations keyed by their IDs.
+async fn applied_migrations(
+    conn: FirestoreConnection,
+    tracking_collection: &str,
+) -> Result<HashMap<String, (u64, String)>, BoxError> {
+    let full_path = format!("{}/{}", conn.1, tracking_collection);
+    let collection = collect_collection(conn, full_path).await?;
+    Ok(collection
+        .documents
+        .into_iter()
+        .map(|document| {
+            let version = match document.data.get("version") {
+                Some(ValueType::IntegerValue(version)) => *version as u64,
+                _ => parse_version(&document.id).unwrap_or_default(),
+            };
+            let checksum = match document.data.get("checksum") {
+                Some(ValueType::StringValue(checksum)) => checksum.clone(),
+                _ => String::new(),
+            };
+            (document.id, (version, checksum))
+        })
+        .collect())
+}
+
+/// Creates the record of the applied migration.
+///
+/// The record must not exist yet, so that a concurrent run applying the same migration is detected.
+async fn record_migration(
+    conn: FirestoreConnection,
+    tracking_collection: &str,
+    migration: &VersionedMigration,
+    migration_report: &MigrationReport,
+) -> Result<(), BoxError> {
+    let (parent, collection_id) = tracking_collection
+        .rsplit_once('/')
+        .unwrap_or(("", tracking_collection));
+    let data = vec![
+        ("version", ValueType::IntegerValue(migration.version as i64)),
+        (
+            "checksum",
+            ValueType::StringValue(migration.checksum.clone()),
+        ),
+        (
+            "migrated",
+            ValueType::IntegerValue(migration_report.migrated.len() as i64),
+        ),
+        ("appliedAt", ValueType::ServerTimestamp),
+    ];
+    let collection = CollectionData {
+        id: collection_id.to_string(),
+        documents: vec![DocumentData {
+            id: migration.id.clone(),
+            data: data
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            subcollections: None,
+        }],
+    };
+    let options = SeedOptions {
+        precondition: SeedPrecondition::MustNotExist,
+        ..SeedOptions::default()
+    };
+    let report =
+        seed_collection_with_options(conn, &collection, &format!("/{}", parent), &options).await?;
+    if !report.skipped.is_empty() {
+        return Err(format!(
+            "Migration {} has been recorded by another run meanwhile",
+            migration.id
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn read_migration(path: &Path, version: u64, id: String) -> Result<VersionedMigration, BoxError> {
+    let content = fs::read_to_string(path)?;
+    let migration = serde_json::from_str(&content)
+        .map_err(|error| format!("Could not parse {}: {}", path.display(), error))?;
+    Ok(VersionedMigration {
+        version,
+        id,
+        checksum: checksum(content.as_bytes()),
+        migration,
+    })
+}
+
+/// Parses the leading number of a migration file name.
+fn parse_version(id: &str) -> Option<u64> {
+    let digits = id
+        .split(|character: char| !character.is_ascii_digit())
+        .next()?;
+    let rest = &id[digits.len()..];
+    if rest.is_empty() || rest.starts_with('_') {
+        digits.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Checksum of the migration file, recorded with the applied migration to detect later edits.
+fn checksum(content: &[u8]) -> String {
+    format!("{:016x}", fnv1a(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIGRATION: &str = r#"{"collection": "users", "operations": []}"#;
+
+    /// Creates an empty directory with the given files for the test.
+    fn migrations_directory(test: &str, files: &[(&str, &str)]) -> String {
+        let directory =
+            std::env::temp_dir().join(format!("migrations-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        for (name, content) in files {
+            fs::write(directory.join(name), content).unwrap();
+        }
+        directory.to_str().unwrap().to_string()
+    }
+
+    fn versioned(version: u64, id: &str, checksum: &str) -> VersionedMigration {
+        VersionedMigration {
+            version,
+            id: id.to_string(),
+            checksum: checksum.to_string(),
+            migration: serde_json::from_str(MIGRATION).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_load_migrations() {
+        let directory = migrations_directory(
+            "load",
+            &[
+                ("0002_add_role.json", MIGRATION),
+                ("0001_initial.json", MIGRATION),
+                ("README.md", "not a migration"),
+            ],
+        );
+
+        let migrations = load_migrations(&directory).unwrap();
+
+        let ids = migrations
+            .iter()
+            .map(|migration| (migration.version, migration.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![(1, "0001_initial"), (2, "0002_add_role")]);
+        assert_eq!(migrations[0].checksum, checksum(MIGRATION.as_bytes()));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_migrations_duplicate_version() {
+        let directory = migrations_directory(
+            "duplicate",
+            &[
+                ("0001_initial.json", MIGRATION),
+                ("1_other.json", MIGRATION),
+            ],
+        );
+
+        let error = load_migrations(&directory).unwrap_err();
+
+        assert!(error.to_string().contains("have the same version"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_migrations_invalid_file_name() {
+        let directory = migrations_directory("name", &[("initial.json", MIGRATION)]);
+
+        let error = load_migrations(&directory).unwrap_err();
+
+        assert!(error.to_string().contains("Invalid migration file name"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_check_applied_migrations() {
+        let migrations = vec![
+            versioned(1, "0001_initial", "a"),
+            versioned(2, "0002_add_role", "b"),
+            versioned(3, "0003_rename_email", "c"),
+        ];
+        let applied = |records: &[(&str, u64, &str)]| {
+            records
+                .iter()
+                .map(|(id, version, checksum)| (id.to_string(), (*version, checksum.to_string())))
+                .collect::<HashMap<_, _>>()
+        };
+
+        assert!(check_applied_migrations(&migrations, &applied(&[])).is_ok());
+        assert!(check_applied_migrations(
+            &migrations,
+            &applied(&[("0001_initial", 1, "a"), ("0002_add_role", 2, "b")])
+        )
+        .is_ok());
+
+        let changed = check_applied_migrations(&migrations, &applied(&[("0001_initial", 1, "x")]))
+            .unwrap_err();
+        assert!(changed
+            .to_string()
+            .contains("0001_initial has been changed"));
+
+        let out_of_order =
+            check_applied_migrations(&migrations, &applied(&[("0002_add_role", 2, "b")]))
+                .unwrap_err();
+        assert!(out_of_order
+            .to_string()
+            .contains("0001_initial is older than the latest applied migration"));
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("0003_rename_email"), Some(3));
+        assert_eq!(parse_version("12"), Some(12));
+        assert_eq!(parse_version("v1_initial"), None);
+        assert_eq!(parse_version("1a_initial"), None);
+        assert_eq!(parse_version("_initial"), None);
+    }
+}
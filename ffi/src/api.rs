@@ -2,14 +2,14 @@ use rust_firestore_snapshot_core::firestore::FirestoreConnection;
 
 use std::{
     fs::{read_to_string, File},
-    io::Write, collections::HashMap,
+    io::Write,
 };
 
 use anyhow::Result;
 use rust_firestore_snapshot_core::firestore::{
     collect::collect_collection,
     get_client,
-    seed::{seed_collection, CollectionData},
+    seed::{seed_collection_with_options, CollectionData, SeedOptions},
 };
 
 // pub fn init(project_id: String, token: String) -> *mut c_void {
@@ -52,6 +52,38 @@ pub async fn update_collection(
     token: String,
     collection_path: String,
     input_file_path: String,
+) -> Result<()> {
+    seed_from_file(
+        project_id,
+        token,
+        collection_path,
+        input_file_path,
+        SeedOptions::default(),
+    )
+    .await
+}
+
+/// Merges the collection from the file into Firestore, preserving fields not present in the file.
+#[tokio::main()]
+pub async fn merge_collection(
+    project_id: String,
+    token: String,
+    collection_path: String,
+    input_file_path: String,
+) -> Result<()> {
+    let options = SeedOptions {
+        merge: true,
+        ..SeedOptions::default()
+    };
+    seed_from_file(project_id, token, collection_path, input_file_path, options).await
+}
+
+async fn seed_from_file(
+    project_id: String,
+    token: String,
+    collection_path: String,
+    input_file_path: String,
+    options: SeedOptions,
 ) -> Result<()> {
     let firestore_connection = obtain_connection(project_id, token).await;
 
@@ -60,7 +92,9 @@ pub async fn update_collection(
 
     let post_body: CollectionData = serde_json::from_str(&json_string).unwrap();
 
-    match seed_collection(firestore_connection, &post_body, &collection_path).await {
+    match seed_collection_with_options(firestore_connection, &post_body, &collection_path, &options)
+        .await
+    {
         Ok(_) => println!("Collection updated successfully"),
         Err(_error) => panic!(),
     };
@@ -75,6 +109,5 @@ async fn obtain_connection(project_id: String, token: String) -> FirestoreConnec
         &project_id,
     );
     let parent = format!("projects/{}/databases/(default)/documents", project_id);
-    let firestore_conn = FirestoreConnection(client, parent);
-    firestore_conn
+    FirestoreConnection::new(client, parent)
 }
@@ -75,6 +75,37 @@ pub extern "C" fn wire_update_collection(
     )
 }
 
+#[no_mangle]
+pub extern "C" fn wire_merge_collection(
+    port_: i64,
+    project_id: *mut wire_uint_8_list,
+    token: *mut wire_uint_8_list,
+    collection_path: *mut wire_uint_8_list,
+    input_file_path: *mut wire_uint_8_list,
+) {
+    FLUTTER_RUST_BRIDGE_HANDLER.wrap(
+        WrapInfo {
+            debug_name: "merge_collection",
+            port: Some(port_),
+            mode: FfiCallMode::Normal,
+        },
+        move || {
+            let api_project_id = project_id.wire2api();
+            let api_token = token.wire2api();
+            let api_collection_path = collection_path.wire2api();
+            let api_input_file_path = input_file_path.wire2api();
+            move |task_callback| {
+                merge_collection(
+                    api_project_id,
+                    api_token,
+                    api_collection_path,
+                    api_input_file_path,
+                )
+            }
+        },
+    )
+}
+
 // Section: wire structs
 
 #[repr(C)]
@@ -25,6 +25,13 @@ abstract class FirestoreClient {
       required String collectionPath,
       required String inputFilePath,
       dynamic hint});
+
+  Future<void> mergeCollection(
+      {required String projectId,
+      required String token,
+      required String collectionPath,
+      required String inputFilePath,
+      dynamic hint});
 }
 
 class FirestoreClientImpl extends FlutterRustBridgeBase<FirestoreClientWire>
@@ -78,6 +85,28 @@ class FirestoreClientImpl extends FlutterRustBridgeBase<FirestoreClientWire>
         hint: hint,
       ));
 
+  Future<void> mergeCollection(
+          {required String projectId,
+          required String token,
+          required String collectionPath,
+          required String inputFilePath,
+          dynamic hint}) =>
+      executeNormal(FlutterRustBridgeTask(
+        callFfi: (port) => inner.wire_merge_collection(
+            port,
+            _api2wire_String(projectId),
+            _api2wire_String(token),
+            _api2wire_String(collectionPath),
+            _api2wire_String(inputFilePath)),
+        parseSuccessData: _wire2api_unit,
+        constMeta: const FlutterRustBridgeTaskConstMeta(
+          debugName: "merge_collection",
+          argNames: ["projectId", "token", "collectionPath", "inputFilePath"],
+        ),
+        argValues: [projectId, token, collectionPath, inputFilePath],
+        hint: hint,
+      ));
+
   // Section: api2wire
   ffi.Pointer<wire_uint_8_list> _api2wire_String(String raw) {
     return _api2wire_uint_8_list(utf8.encoder.convert(raw));
@@ -188,6 +217,38 @@ class FirestoreClientWire implements FlutterRustBridgeWireBase {
           ffi.Pointer<wire_uint_8_list>,
           ffi.Pointer<wire_uint_8_list>)>();
 
+  void wire_merge_collection(
+    int port_,
+    ffi.Pointer<wire_uint_8_list> project_id,
+    ffi.Pointer<wire_uint_8_list> token,
+    ffi.Pointer<wire_uint_8_list> collection_path,
+    ffi.Pointer<wire_uint_8_list> input_file_path,
+  ) {
+    return _wire_merge_collection(
+      port_,
+      project_id,
+      token,
+      collection_path,
+      input_file_path,
+    );
+  }
+
+  late final _wire_merge_collectionPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(
+              ffi.Int64,
+              ffi.Pointer<wire_uint_8_list>,
+              ffi.Pointer<wire_uint_8_list>,
+              ffi.Pointer<wire_uint_8_list>,
+              ffi.Pointer<wire_uint_8_list>)>>('wire_merge_collection');
+  late final _wire_merge_collection = _wire_merge_collectionPtr.asFunction<
+      void Function(
+          int,
+          ffi.Pointer<wire_uint_8_list>,
+          ffi.Pointer<wire_uint_8_list>,
+          ffi.Pointer<wire_uint_8_list>,
+          ffi.Pointer<wire_uint_8_list>)>();
+
   ffi.Pointer<wire_uint_8_list> new_uint_8_list(
     int len,
   ) {
@@ -2,12 +2,26 @@
 
 use futures::future::TryFutureExt;
 use futures::{try_join, StreamExt};
+use percent_encoding::percent_decode_str;
+use rust_firestore_snapshot_core::firestore::bulk::bulk_seed_collection;
 use rust_firestore_snapshot_core::firestore::collect::collect_collection;
-use rust_firestore_snapshot_core::firestore::seed::{seed_collection, CollectionData};
+use rust_firestore_snapshot_core::firestore::copy::copy_collection;
+use rust_firestore_snapshot_core::firestore::delete::delete_recursive;
+use rust_firestore_snapshot_core::firestore::fanout::{
+    resolve_parent_pattern, seed_collection_fan_out, ParentDocuments,
+};
+use rust_firestore_snapshot_core::firestore::plan::plan_seed;
+use rust_firestore_snapshot_core::firestore::references::Snapshot;
+use rust_firestore_snapshot_core::firestore::replace::{replace_collection, ReplaceReport};
+use rust_firestore_snapshot_core::firestore::retry::RetryPolicy;
+use rust_firestore_snapshot_core::firestore::seed::{
+    seed_collection_with_options, CollectionData, PathRemap, SeedOptions, SeedPrecondition,
+    SeedReport,
+};
 use rust_firestore_snapshot_core::firestore::{BoxError, FirestoreClient, FirestoreConnection};
 
-use std::{convert::Infallible, env};
-use std::{net::SocketAddr};
+use std::{collections::BTreeMap, convert::Infallible, env};
+use std::{net::SocketAddr, time::Duration};
 
 use hyper::{
     service::{make_service_fn, service_fn},
@@ -23,7 +37,8 @@ pub async fn run_http_server() {
         try_join!(get_client_with_fallback(), get_project_id_with_fallback(),)
             .expect("Could not connect to Firestore. Make sure the environment variables ");
     let parent = format!("projects/{}/databases/(default)/documents", project_id);
-    let firestore_conn = FirestoreConnection(client, parent);
+    let firestore_conn =
+        FirestoreConnection::new(client, parent).with_retry_policy(get_retry_policy());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], get_port()));
     let make_svc = make_service_fn(move |_conn| {
@@ -61,6 +76,20 @@ async fn root(
             Usage:
             GET (/{path_to_collection}) - returns a JSON file containing data of the collection
             POST (/{path_to_collection}) - updates the collection with data from JSON passed as a body of request
+            PATCH (/{path_to_collection}) - merges data from JSON passed as a body of request into the collection, preserving fields not present in the body
+              POST and PATCH with `*` in the path (e.g. `/tenants/*`) seed the collection under every matching parent document
+              POST and PATCH with `?copy_from={path_to_collection}` copy the collection from Firestore instead of the body, `?from_project={id}` reads it from another project
+              POST and PATCH accept `?precondition=create-only` or `?precondition=update-only` to write only missing or only existing documents
+              POST and PATCH accept `?dry_run=true` to return the plan of the writes without writing anything
+              POST and PATCH accept `?bulk=true` to write documents independently and return the status of each of them
+                with `*` in the path both return an object with the plan or the status of every matching parent document
+              POST, PATCH and PUT accept `?concurrency={n}` to commit up to n batches at the same time
+              POST and PATCH accept `?regenerate_ids=true` to write documents with fresh auto-IDs and return the mapping of the IDs
+              POST, PATCH and PUT accept `?remap_project={id}`, `?remap_database={id}` and `?remap_prefix={from}={to}` to rewrite references, prefixes also rewrite document paths
+            PUT (/{path_to_collection}) - replaces the collection with data from JSON passed as a body of request, deleting documents not present in the body
+              requires `?confirm=true`, use `?dry_run=true` to only preview the changes
+            DELETE (/{path_to_collection_or_document}) - deletes the collection or the document together with all subcollections
+              requires `?confirm=true`
             "#,
         ),
         (&Method::GET, path) => {
@@ -73,9 +102,43 @@ async fn root(
         //     let r = post_greeting(firestore_conn, req).await;
         //     r.unwrap_or(String::from_str("~~ error happened~~").expect("Unable to unwrap string"))
         // }
-        (&Method::POST, _) => {
-            update_collection(firestore_conn, req).await?;
-            String::from("")
+        (&Method::POST, _) | (&Method::PATCH, _) => {
+            let options = seed_options(&req, req.method() == Method::PATCH)?;
+            if req.uri().path().contains('*') && query_param(&req, "copy_from").is_some() {
+                return Err("`copy_from` can't be combined with `*` in the path".into());
+            }
+            if query_flag(&req, "dry_run") {
+                plan_update(firestore_conn, req, options).await?
+            } else if query_flag(&req, "bulk") {
+                bulk_update(firestore_conn, req, options).await?
+            } else if let Some(source_path) = query_param(&req, "copy_from") {
+                copy(firestore_conn, req, source_path, options).await?
+            } else if req.uri().path().contains('*') {
+                fan_out_update(firestore_conn, req, options).await?
+            } else {
+                let report = update_collection(firestore_conn, req, options).await?;
+                serde_json::to_string_pretty(&report)?
+            }
+        }
+        (&Method::PUT, _) => {
+            let options = seed_options(&req, false)?;
+            let dry_run = query_flag(&req, "dry_run");
+            if !dry_run && !query_flag(&req, "confirm") {
+                return Err("Replacing a collection deletes documents. Pass `?confirm=true` to proceed or `?dry_run=true` to preview the changes.".into());
+            }
+            let report = replace(firestore_conn, req, options, dry_run).await?;
+            serde_json::to_string_pretty(&report)?
+        }
+        (&Method::DELETE, path) => {
+            if !query_flag(&req, "confirm") {
+                return Err("Deleting is irreversible. Pass `?confirm=true` to proceed.".into());
+            }
+            println!("DELETE {}", path);
+            let deleted = delete_recursive(firestore_conn, path, |progress| {
+                println!("deleted {}/{} documents", progress.deleted, progress.total)
+            })
+            .await?;
+            format!("{} documents deleted", deleted)
         }
         _ => "Unrecognizable command".to_string(),
     };
@@ -86,8 +149,134 @@ async fn root(
 async fn update_collection(
     firestore_conn: FirestoreConnection,
     mut req: Request<Body>,
-) -> Result<(), BoxError> {
+    options: SeedOptions,
+) -> Result<SeedReport, BoxError> {
     println!("Updating collection...");
+    let post_body = read_collection_body(&mut req).await?;
+
+    let collection_path = req.uri().path();
+    println!("seeding collection at {collection_path}");
+    seed_collection_with_options(firestore_conn, &post_body, collection_path, &options)
+        .await
+        .map_err(|err| err.into())
+}
+
+async fn plan_update(
+    firestore_conn: FirestoreConnection,
+    mut req: Request<Body>,
+    options: SeedOptions,
+) -> Result<String, BoxError> {
+    let post_body = read_collection_body(&mut req).await?;
+
+    let collection_path = req.uri().path();
+    if collection_path.contains('*') {
+        let mut plans = BTreeMap::new();
+        for parent in resolve_parent_pattern(firestore_conn.clone(), collection_path).await? {
+            println!("planning seeding collection at {parent}");
+            let plan = plan_seed(firestore_conn.clone(), &post_body, &parent, &options).await?;
+            plans.insert(parent, plan);
+        }
+        return Ok(serde_json::to_string_pretty(&plans)?);
+    }
+    println!("planning seeding collection at {collection_path}");
+    let plan = plan_seed(firestore_conn, &post_body, collection_path, &options).await?;
+    Ok(serde_json::to_string_pretty(&plan)?)
+}
+
+async fn bulk_update(
+    firestore_conn: FirestoreConnection,
+    mut req: Request<Body>,
+    options: SeedOptions,
+) -> Result<String, BoxError> {
+    let post_body = read_collection_body(&mut req).await?;
+
+    let collection_path = req.uri().path();
+    if collection_path.contains('*') {
+        let mut reports = BTreeMap::new();
+        for parent in resolve_parent_pattern(firestore_conn.clone(), collection_path).await? {
+            println!("bulk seeding collection at {parent}");
+            let report =
+                bulk_seed_collection(firestore_conn.clone(), &post_body, &parent, &options).await?;
+            reports.insert(parent, report);
+        }
+        return Ok(serde_json::to_string_pretty(&reports)?);
+    }
+    println!("bulk seeding collection at {collection_path}");
+    let report =
+        bulk_seed_collection(firestore_conn, &post_body, collection_path, &options).await?;
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+async fn fan_out_update(
+    firestore_conn: FirestoreConnection,
+    mut req: Request<Body>,
+    options: SeedOptions,
+) -> Result<String, BoxError> {
+    let post_body = read_collection_body(&mut req).await?;
+
+    let pattern = req.uri().path().to_string();
+    println!("seeding collection under parents matching {pattern}");
+    let report = seed_collection_fan_out(
+        firestore_conn,
+        &post_body,
+        &ParentDocuments::Pattern(pattern),
+        &options,
+    )
+    .await?;
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+async fn copy(
+    firestore_conn: FirestoreConnection,
+    req: Request<Body>,
+    source_path: String,
+    options: SeedOptions,
+) -> Result<String, BoxError> {
+    let source_conn = match query_param(&req, "from_project") {
+        Some(project_id) => FirestoreConnection(
+            firestore_conn.0.clone(),
+            format!("projects/{}/databases/(default)/documents", project_id),
+            firestore_conn.2.clone(),
+        ),
+        None => firestore_conn.clone(),
+    };
+
+    let parent_path = req.uri().path();
+    println!("copying collection {source_path} to {parent_path}");
+    let report = copy_collection(
+        source_conn,
+        &source_path,
+        firestore_conn,
+        parent_path,
+        &options,
+    )
+    .await?;
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+async fn replace(
+    firestore_conn: FirestoreConnection,
+    mut req: Request<Body>,
+    options: SeedOptions,
+    dry_run: bool,
+) -> Result<ReplaceReport, BoxError> {
+    println!("Replacing collection...");
+    let post_body = read_collection_body(&mut req).await?;
+
+    let collection_path = req.uri().path();
+    println!("replacing collection at {collection_path} (dry run: {dry_run})");
+    replace_collection(
+        firestore_conn,
+        &post_body,
+        collection_path,
+        &options,
+        dry_run,
+    )
+    .await
+    .map_err(|err| err.into())
+}
+
+async fn read_collection_body(req: &mut Request<Body>) -> Result<CollectionData, BoxError> {
     // asynchronously concatenate chunks of the body
     let mut body = Vec::new();
     while let Some(chunk) = req.body_mut().next().await {
@@ -95,15 +284,96 @@ async fn update_collection(
     }
     println!("received {} bytes", body.len());
     // try to parse as json with serde_json
-    let post_body: CollectionData = serde_json::from_slice(&body)?;
+    let snapshot: Snapshot = serde_json::from_slice(&body)?;
+    if !snapshot.references.is_empty() {
+        return Err(
+            "Snapshots with referenced documents are not supported by the server, seed them with the CLI"
+                .into(),
+        );
+    }
+    Ok(snapshot.collection)
+}
 
-    println!("parsed body:\n{:?} ", post_body);
+fn seed_options(req: &Request<Body>, merge: bool) -> Result<SeedOptions, BoxError> {
+    let precondition = match query_param(req, "precondition").as_deref() {
+        None => SeedPrecondition::None,
+        Some("create-only") => SeedPrecondition::MustNotExist,
+        Some("update-only") => SeedPrecondition::MustExist,
+        Some(other) => return Err(format!("Unknown precondition: {}", other).into()),
+    };
+    let concurrency = match query_param(req, "concurrency") {
+        None => 1,
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid concurrency: {}", value))?,
+    };
+    Ok(SeedOptions {
+        merge,
+        precondition,
+        concurrency,
+        // a replace mirrors the documents by their IDs
+        regenerate_ids: req.method() != Method::PUT && query_flag(req, "regenerate_ids"),
+        remap: PathRemap {
+            project: query_param(req, "remap_project"),
+            database: query_param(req, "remap_database"),
+            prefixes: match query_param(req, "remap_prefix") {
+                None => vec![],
+                Some(rule) => match rule.split_once('=') {
+                    Some((from, to)) => vec![(from.to_string(), to.to_string())],
+                    None => return Err(format!("Invalid remap_prefix: {}", rule).into()),
+                },
+            },
+        },
+        ..SeedOptions::default()
+    })
+}
 
-    let collection_path = req.uri().path();
-    println!("seeding collection at {collection_path}");
-    seed_collection(firestore_conn, &post_body, &collection_path)
-        .await
-        .map_err(|err| err.into()).map(|_|())
+fn query_flag(req: &Request<Body>, name: &str) -> bool {
+    matches!(query_param(req, name).as_deref(), Some("") | Some("true"))
+}
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query().and_then(|query| {
+        query.split('&').find_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => {
+                    Some(percent_decode_str(value).decode_utf8_lossy().into_owned())
+                }
+                (Some(key), None) if key == name => Some(String::new()),
+                _ => None,
+            }
+        })
+    })
+}
+
+/// Reads the retry policy of Firestore calls from the `RETRY_MAX_ATTEMPTS`, `RETRY_INITIAL_BACKOFF_MS`,
+/// `RETRY_MAX_BACKOFF_MS`, `RETRY_MULTIPLIER`, `RETRY_JITTER` and `RETRY_CODES` environment variables,
+/// using defaults for the missing ones.
+/// `RETRY_CODES` is a comma separated list of status codes, e.g. `UNAVAILABLE,ABORTED`.
+fn get_retry_policy() -> RetryPolicy {
+    fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+        env::var(name).ok().and_then(|value| value.parse().ok())
+    }
+    let default = RetryPolicy::default();
+    RetryPolicy {
+        max_attempts: var("RETRY_MAX_ATTEMPTS").unwrap_or(default.max_attempts),
+        initial_backoff: var("RETRY_INITIAL_BACKOFF_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(default.initial_backoff),
+        max_backoff: var("RETRY_MAX_BACKOFF_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(default.max_backoff),
+        multiplier: var("RETRY_MULTIPLIER").unwrap_or(default.multiplier),
+        jitter: var("RETRY_JITTER").unwrap_or(default.jitter),
+        retriable_codes: match env::var("RETRY_CODES") {
+            Ok(codes) => codes
+                .split(',')
+                .map(|name| RetryPolicy::parse_code(name).expect("Invalid RETRY_CODES"))
+                .collect(),
+            Err(_) => default.retriable_codes,
+        },
+    }
 }
 
 fn get_port() -> u16 {
@@ -139,8 +409,7 @@ async fn get_local_client() -> Result<FirestoreClient, BoxError> {
 }
 
 fn get_token() -> String {
-    let token = env::var("TOKEN").expect("TOKEN env variable has not been set. Please run 'export TOKEN=`gcloud auth print-access-token`' before starting a server.");
-    token
+    env::var("TOKEN").expect("TOKEN env variable has not been set. Please run 'export TOKEN=`gcloud auth print-access-token`' before starting a server.")
 }
 
 async fn get_project_id() -> Result<String, BoxError> {
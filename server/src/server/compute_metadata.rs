@@ -15,7 +15,7 @@ pub async fn get_token() -> std::result::Result<String, BoxError> {
         "instance/service-accounts/default/token?scopes=https://www.googleapis.com/auth/datastore",
     )
     .await?;
-    let body: TokenResponse = serde_json::from_slice(&bytes.to_vec())?;
+    let body: TokenResponse = serde_json::from_slice(&bytes)?;
     let token = body.access_token;
     Ok(token)
 }